@@ -0,0 +1,31 @@
+use std::path::PathBuf;
+use std::{env, fs};
+
+use syntect::dumps::dump_binary;
+use syntect::parsing::syntax_definition::SyntaxDefinition;
+use syntect::parsing::{SyntaxSet, SyntaxSetBuilder};
+
+/// Builds the syntax sets once, the preprocessor loads their dumps:
+/// syntect's default syntaxes with Typst, and only Typst for `minimal_syntaxes`
+fn main() {
+    println!("cargo::rerun-if-changed=res/Typst.sublime-syntax");
+
+    let typst =
+        fs::read_to_string("res/Typst.sublime-syntax").expect("Can't read the Typst syntax");
+    let typst =
+        SyntaxDefinition::load_from_str(&typst, true, None).expect("Syntax data was corrupted");
+
+    let mut syntaxes = SyntaxSet::load_defaults_nonewlines().into_builder();
+    syntaxes.add(typst.clone());
+    write_dump("syntaxes.packdump", &syntaxes.build());
+
+    let mut minimal = SyntaxSetBuilder::new();
+    minimal.add_plain_text_syntax();
+    minimal.add(typst);
+    write_dump("typst.packdump", &minimal.build());
+}
+
+fn write_dump(name: &str, syntaxes: &SyntaxSet) {
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap()).join(name);
+    fs::write(out, dump_binary(syntaxes)).expect("Can't write the syntax dump");
+}
@@ -0,0 +1,602 @@
+//! Names of rendered files, the manifests of the cache and pruning it
+
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use anyhow::anyhow;
+use mdbook_preprocessor::errors::{Error, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::render::get_images;
+use crate::{slash_path, SHORT_HASH_LEN};
+
+/// Where the version of Typst that rendered the cached images is kept, in `typst-img` of the cache
+const TYPST_VERSION_FILE: &str = "typst-version";
+
+/// `manifest.json` of every `typst-img` directory in use: for each rendered block,
+/// hashes of its images. A block is rendered again if its images don't match them,
+/// e.g. after an interrupted build or a manual edit
+#[derive(Default)]
+pub(crate) struct Manifests {
+    dirs: RefCell<BTreeMap<PathBuf, Manifest>>,
+    /// Directories whose manifests differ from their files, only they are written
+    changed: RefCell<BTreeSet<PathBuf>>,
+    /// Blocks seen in this build, identical ones are rendered once
+    seen: RefCell<BTreeSet<PathBuf>>,
+    /// Chapters of every block by its cache key, also across `typst-img` directories
+    chapters: RefCell<BTreeMap<String, Vec<String>>>,
+    /// Blocks whose errors are already reported
+    reported: RefCell<BTreeSet<String>>,
+}
+
+/// Block's cache key → image name → what the image was like when it was rendered
+type Manifest = BTreeMap<String, BTreeMap<String, ImageRecord>>;
+
+/// A rendered image in a manifest. Images with the size and modification time they
+/// were recorded with aren't hashed again, so that rebuilds of `mdbook serve` stay quick
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+enum ImageRecord {
+    Stamped {
+        /// SHA-256 of the image
+        hash: String,
+        size: u64,
+        /// Nanoseconds since the Unix epoch
+        modified: u64,
+    },
+    /// Manifests of older versions only have the hash
+    Hash(String),
+}
+
+impl ImageRecord {
+    fn new(path: &Path) -> Option<Self> {
+        let hash = file_hash(path)?;
+        Some(match file_stamp(path) {
+            Some((size, modified)) => ImageRecord::Stamped {
+                hash,
+                size,
+                modified,
+            },
+            None => ImageRecord::Hash(hash),
+        })
+    }
+
+    /// Whether the image at `path` is still the recorded one, stamping it if it is
+    fn check(&mut self, path: &Path) -> bool {
+        let hash = match self {
+            ImageRecord::Stamped {
+                hash,
+                size,
+                modified,
+            } => {
+                if file_stamp(path) == Some((*size, *modified)) {
+                    return true;
+                }
+                hash
+            }
+            ImageRecord::Hash(hash) => hash,
+        };
+
+        // Touched files, e.g. after a checkout, get their new stamp
+        file_hash(path).as_ref() == Some(hash) && {
+            if let Some(record) = ImageRecord::new(path) {
+                *self = record;
+            }
+            true
+        }
+    }
+}
+
+impl Manifests {
+    /// Whether the recorded images of `file` (a rendered file without the page number
+    /// and extension) are all there and unchanged
+    pub(crate) fn is_valid(&self, file: &Path) -> bool {
+        let dir = file.parent().unwrap();
+        let key = file.file_name().unwrap().to_string_lossy();
+
+        let mut dirs = self.dirs.borrow_mut();
+        let manifest = dirs.entry(dir.to_path_buf()).or_insert_with(|| {
+            fs::read_to_string(dir.join("manifest.json"))
+                .ok()
+                .and_then(|json| serde_json::from_str(&json).ok())
+                .unwrap_or_default()
+        });
+
+        let Some(images) = manifest.get_mut(key.as_ref()) else {
+            return false;
+        };
+        let recorded = images.clone();
+        let valid = !images.is_empty()
+            && images
+                .iter_mut()
+                .all(|(name, record)| record.check(&dir.join(name)));
+        // Touched images get new stamps
+        if *images != recorded {
+            self.changed.borrow_mut().insert(dir.to_path_buf());
+        }
+        valid
+    }
+
+    /// Whether `file` is seen for the first time in this build, `chapter` is where it is now
+    pub(crate) fn first_seen(&self, file: &Path, chapter: &str) -> bool {
+        let key = file.file_name().unwrap().to_string_lossy().into_owned();
+        self.chapters
+            .borrow_mut()
+            .entry(key)
+            .or_default()
+            .push(chapter.to_owned());
+        self.seen.borrow_mut().insert(file.to_path_buf())
+    }
+
+    /// The error of a block with its other places, or `None` if the same source has already failed.
+    /// All chapters are prepared before rendering starts, so they are known by then
+    pub(crate) fn annotate(&self, file: &Path, error: String) -> Option<String> {
+        let key = file.file_name().unwrap().to_string_lossy().into_owned();
+        if !self.reported.borrow_mut().insert(key.clone()) {
+            return None;
+        }
+        let all = self.chapters.borrow();
+        let Some(chapters) = all.get(&key).filter(|chapters| chapters.len() > 1) else {
+            return Some(error);
+        };
+        let mut unique = chapters.clone();
+        unique.dedup();
+        Some(format!(
+            "{}\n\nThe same block is used {} times, in chapters {}\n",
+            error.trim_end(),
+            chapters.len(),
+            unique
+                .iter()
+                .map(|chapter| format!("\"{chapter}\""))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))
+    }
+
+    /// Records the freshly rendered images of `file`
+    pub(crate) fn record(&self, file: &Path, extension: &'static str) {
+        let dir = file.parent().unwrap();
+        let key = file.file_name().unwrap().to_string_lossy().into_owned();
+        let images = get_images(file.to_path_buf(), extension)
+            .filter_map(|name| Some((name.clone(), ImageRecord::new(&dir.join(name))?)))
+            .collect();
+
+        self.dirs
+            .borrow_mut()
+            .entry(dir.to_path_buf())
+            .or_default()
+            .insert(key, images);
+        self.changed.borrow_mut().insert(dir.to_path_buf());
+    }
+
+    /// Writes the changed manifests, keeping only the `used` blocks, if given.
+    /// The others are left alone, so that `mdbook serve` and git don't see a change
+    pub(crate) fn save(&self, used: Option<&BTreeSet<PathBuf>>) {
+        let mut changed = self.changed.borrow_mut();
+        for (dir, manifest) in self.dirs.borrow_mut().iter_mut() {
+            if let Some(used) = used {
+                let count = manifest.len();
+                manifest.retain(|key, _| used.contains(&dir.join(key)));
+                if manifest.len() != count {
+                    changed.insert(dir.clone());
+                }
+            }
+            if !changed.contains(dir) {
+                continue;
+            }
+
+            let path = dir.join("manifest.json");
+            let json = serde_json::to_string_pretty(manifest).expect("Manifest is serializable");
+            if let Err(e) = fs::write(&path, json) {
+                log::warn!("Can't write {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+fn file_hash(path: &Path) -> Option<String> {
+    let content = fs::read(path).ok()?;
+    Some(format!("{:x}", Sha256::digest(content)))
+}
+
+/// Size and modification time of a file, in nanoseconds since the Unix epoch
+fn file_stamp(path: &Path) -> Option<(u64, u64)> {
+    let metadata = fs::metadata(path).ok()?;
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some((metadata.len(), modified.as_nanos() as u64))
+}
+
+/// Name of the cached files for a block, everything that changes the output must be hashed.
+/// The preamble and inputs are length-prefixed, so that they can't be confused with the source
+pub(crate) fn cache_key(
+    src: &str,
+    preamble: Option<&str>,
+    inputs: &BTreeMap<String, String>,
+    dependencies: &BTreeMap<String, String>,
+    args: &[String],
+    typst_version: &str,
+) -> String {
+    let mut input = match preamble {
+        Some(preamble) => format!("preamble {}\n{}{}", preamble.len(), preamble, src),
+        None => format!("nopreamble\n{}", src),
+    };
+    // Blocks without inputs keep their old names
+    for (key, value) in inputs.iter().rev() {
+        input = format!(
+            "input {} {}\n{}{}{}",
+            key.len(),
+            value.len(),
+            key,
+            value,
+            input
+        );
+    }
+    // And without imported files
+    for (path, hash) in dependencies.iter().rev() {
+        input = format!("dependency {}\n{}{}\n{}", path.len(), path, hash, input);
+    }
+    // As do blocks compiled without extra arguments
+    for arg in args.iter().rev() {
+        input = format!("arg {}\n{}{}", arg.len(), arg, input);
+    }
+    sha256_hash(&format!("{}\n{}", typst_version.trim(), input))
+}
+
+/// Local files imported or included by `src`, relative to `root`, with hashes of their contents.
+/// Only paths in string literals are found, e.g. `#import "utils.typ": *`, files that imported
+/// Typst files import are followed as well. Missing files and files outside `root` are skipped
+pub(crate) fn dependencies(src: &str, root: &Path) -> BTreeMap<String, String> {
+    let mut found = BTreeMap::new();
+    // Files to scan with their directories relative to the root, relative paths start there
+    let mut pending = vec![(src.to_owned(), PathBuf::new())];
+
+    while let Some((text, dir)) = pending.pop() {
+        for path in imported_paths(&text) {
+            // Absolute paths start at the root
+            let mut relative = PathBuf::new();
+            let mut inside = true;
+            for component in dir.join(path).components() {
+                match component {
+                    Component::Normal(part) => relative.push(part),
+                    Component::ParentDir => inside &= relative.pop(),
+                    _ => {}
+                }
+            }
+
+            let key = slash_path(&relative);
+            if !inside || found.contains_key(&key) {
+                continue;
+            }
+            let Ok(content) = fs::read(root.join(&relative)) else {
+                continue;
+            };
+
+            found.insert(key, format!("{:x}", Sha256::digest(&content)));
+            if relative
+                .extension()
+                .is_some_and(|extension| extension == "typ")
+            {
+                let dir = relative.parent().map(Path::to_path_buf).unwrap_or_default();
+                pending.push((String::from_utf8_lossy(&content).into_owned(), dir));
+            }
+        }
+    }
+
+    found
+}
+
+/// Paths after `import` and `include` in Typst code, without packages such as `@preview/cetz:0.3.0`
+fn imported_paths(text: &str) -> Vec<&str> {
+    let mut paths = vec![];
+    for keyword in ["import", "include"] {
+        for (start, _) in text.match_indices(keyword) {
+            // A keyword, not a part of a name such as `my-import`
+            if text[..start]
+                .chars()
+                .next_back()
+                .is_some_and(|c| c.is_alphanumeric() || c == '_' || c == '-')
+            {
+                continue;
+            }
+            let rest = text[start + keyword.len()..].trim_start();
+            if let Some(rest) = rest.strip_prefix('"')
+                && let Some(end) = rest.find('"')
+                && !rest[..end].is_empty()
+                && !rest.starts_with('@')
+            {
+                paths.push(&rest[..end]);
+            }
+        }
+    }
+    paths
+}
+
+pub(crate) fn typst_version(typst: &Path) -> Result<String> {
+    let output = std::process::Command::new(typst)
+        .arg("--version")
+        .output()?;
+
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    } else {
+        Err(anyhow!(
+            "`typst --version` failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+/// Output of `typst --version` for the images in the cache, see `record_typst_version`
+pub(crate) fn recorded_typst_version(cache_dir: &Path) -> Option<String> {
+    fs::read_to_string(cache_dir.join("typst-img").join(TYPST_VERSION_FILE)).ok()
+}
+
+/// Keeps the version of Typst next to the images it rendered. Their names depend on it,
+/// so builds without Typst, e.g. after `mdbook-typst-highlight render` in CI, can still find them
+pub(crate) fn record_typst_version(cache_dir: &Path, version: &str) {
+    let dir = cache_dir.join("typst-img");
+    // Books without rendered images get no directory just for this
+    if !dir.exists() || recorded_typst_version(cache_dir).as_deref() == Some(version) {
+        return;
+    }
+    let path = dir.join(TYPST_VERSION_FILE);
+    if let Err(e) = fs::write(&path, version) {
+        log::warn!("Can't write {}: {}", path.display(), e);
+    }
+}
+
+/// `(major, minor, patch)` of the first version in the text, e.g. of `typst 0.13.1 (8ace67d9)`.
+/// A missing patch is 0, anything that isn't a version gives `None`
+pub(crate) fn parse_version(text: &str) -> Option<(u32, u32, u32)> {
+    text.split_whitespace().find_map(|word| {
+        let mut parts = word.strip_prefix('v').unwrap_or(word).split('.');
+        let major = parts.next()?.parse().ok()?;
+        let minor = parts.next()?.parse().ok()?;
+        // Pre-releases, such as `0.14.0-rc.1`, count as the release
+        let patch = match parts.next() {
+            Some(patch) => patch.split('-').next()?.parse().ok()?,
+            None => 0,
+        };
+        Some((major, minor, patch))
+    })
+}
+
+/// Splits the name of a rendered file into its cache name, the hash in it and the rest,
+/// e.g. `{hash}` and `-1.svg`, or `intro-figure-1_{short hash}` and `-2x-1.png`.
+/// Files not named this way aren't ours
+pub(crate) fn split_cache_name(name: &str) -> Option<(&str, &str, &str)> {
+    fn is_hash(hash: &str, len: usize) -> bool {
+        hash.len() == len && hash.chars().all(|c| c.is_ascii_hexdigit())
+    }
+    fn is_suffix(rest: &str) -> bool {
+        rest.starts_with(['-', '.'])
+    }
+
+    if let Some((hash, rest)) = name.split_at_checked(64)
+        && is_hash(hash, 64)
+        && is_suffix(rest)
+    {
+        return Some((hash, hash, rest));
+    }
+
+    // Slugs don't have underscores, so the first one starts the hash
+    let (slug, hashed) = name.split_once('_')?;
+    let (hash, rest) = hashed.split_at_checked(SHORT_HASH_LEN)?;
+    let is_slug = slug.split('-').all(|word| {
+        !word.is_empty()
+            && word
+                .chars()
+                .all(|c| c.is_alphanumeric() && !c.is_uppercase())
+    });
+    (is_slug && is_hash(hash, SHORT_HASH_LEN) && is_suffix(rest))
+        .then(|| (&name[..slug.len() + 1 + SHORT_HASH_LEN], hash, rest))
+}
+
+pub(crate) fn sha256_hash(input: &str) -> String {
+    let hash = Sha256::digest(input.as_bytes());
+    format!("{:x}", hash)
+}
+
+/// Removes files from `typst-img` and `typst-src` directories under `dir`
+/// that were not used by the book. Only files named like rendered ones are touched, see `split_cache_name`
+pub(crate) fn prune_cache(dir: &Path, used: &BTreeSet<PathBuf>, errors: &mut Vec<Error>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => return errors.push(anyhow!("Can't read {}: {}", dir.display(), e)),
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if !path.is_dir() {
+            continue;
+        }
+
+        if path.ends_with("typst-img") || path.ends_with("typst-src") {
+            let img_dir = path.with_file_name("typst-img");
+
+            for file in fs::read_dir(&path).into_iter().flatten().flatten() {
+                let name = file.file_name().to_string_lossy().into_owned();
+
+                let Some((base, _, _)) = split_cache_name(&name) else {
+                    continue;
+                };
+
+                if !used.contains(&img_dir.join(base))
+                    && let Err(e) = fs::remove_file(file.path())
+                {
+                    errors.push(anyhow!("Can't remove {}: {}", file.path().display(), e));
+                }
+            }
+        } else {
+            prune_cache(&path, used, errors);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    /// Checking cached images on a rebuild, such as one of `mdbook serve`, by their stamps
+    /// against hashing every image, as manifests without stamps need.
+    /// `cargo test --release -- --ignored --nocapture manifest_rebuild`
+    #[test]
+    #[ignore]
+    fn manifest_rebuild() {
+        let dir = std::env::temp_dir()
+            .join(format!(
+                "mdbook-typst-highlight-bench-{}",
+                std::process::id()
+            ))
+            .join("typst-img");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        // 200 blocks with a 50 KB image each
+        let svg = "<path d=\"M 1.892 0 L 1.892 7.293 L 4.653 7.293\"/>\n".repeat(1000);
+        let files = (0..200)
+            .map(|block| {
+                let key = sha256_hash(&block.to_string());
+                fs::write(dir.join(format!("{key}-1.svg")), &svg).unwrap();
+                dir.join(key)
+            })
+            .collect::<Vec<_>>();
+        let manifests = Manifests::default();
+        for file in &files {
+            manifests.record(file, "svg");
+        }
+        manifests.save(None);
+
+        let stamped = fs::read_to_string(dir.join("manifest.json")).unwrap();
+        let mut manifest = serde_json::from_str::<Manifest>(&stamped).unwrap();
+        for record in manifest.values_mut().flat_map(BTreeMap::values_mut) {
+            if let ImageRecord::Stamped { hash, .. } = record {
+                *record = ImageRecord::Hash(hash.clone());
+            }
+        }
+        let hashed = serde_json::to_string(&manifest).unwrap();
+
+        let rebuild = |json: &str| {
+            let mut total = Duration::ZERO;
+            for _ in 0..10 {
+                fs::write(dir.join("manifest.json"), json).unwrap();
+                let started = Instant::now();
+                let manifests = Manifests::default();
+                assert!(files.iter().all(|file| manifests.is_valid(file)));
+                total += started.elapsed();
+            }
+            total / 10
+        };
+        println!(
+            "stamps: {:?}, hashes: {:?}",
+            rebuild(&stamped),
+            rebuild(&hashed)
+        );
+
+        fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn cache_key_depends_on_everything_given_to_typst() {
+        let none = BTreeMap::new();
+        let inputs = BTreeMap::from([("name".to_owned(), "World".to_owned())]);
+        let dependencies = BTreeMap::from([("utils.typ".to_owned(), "0123".to_owned())]);
+        let args = ["--features".to_owned(), "html".to_owned()];
+        let preamble = Some("#set page(width: 5cm)");
+        let version = "typst 0.13.1";
+
+        let base = cache_key("= Title", preamble, &inputs, &dependencies, &args, version);
+        let others = [
+            cache_key("= Title!", preamble, &inputs, &dependencies, &args, version),
+            cache_key("= Title", None, &inputs, &dependencies, &args, version),
+            cache_key(
+                "= Title",
+                Some("#set page(width: 6cm)"),
+                &inputs,
+                &dependencies,
+                &args,
+                version,
+            ),
+            cache_key("= Title", preamble, &none, &dependencies, &args, version),
+            cache_key("= Title", preamble, &inputs, &none, &args, version),
+            cache_key("= Title", preamble, &inputs, &dependencies, &[], version),
+            cache_key(
+                "= Title",
+                preamble,
+                &inputs,
+                &dependencies,
+                &args,
+                "typst 0.14.0",
+            ),
+        ];
+        for other in others {
+            assert_ne!(base, other);
+        }
+
+        // The preamble and the source can't trade text
+        assert_ne!(
+            cache_key("b", Some("a"), &none, &none, &[], version),
+            cache_key("", Some("ab"), &none, &none, &[], version)
+        );
+        // Nor can an input's key and value
+        let input = |key: &str, value: &str| BTreeMap::from([(key.to_owned(), value.to_owned())]);
+        assert_ne!(
+            cache_key("", None, &input("ab", "c"), &none, &[], version),
+            cache_key("", None, &input("a", "bc"), &none, &[], version)
+        );
+    }
+
+    #[test]
+    fn imported_paths_finds_local_files_only() {
+        let src = concat!(
+            "#import \"utils.typ\": *\n",
+            "#import \"@preview/cetz:0.3.0\"\n",
+            "#include  \"chapters/intro.typ\"\n",
+            "#let my-import = \"not.typ\"\n",
+            "#image(\"logo.svg\")\n",
+            "#import \"\": x\n",
+        );
+        assert_eq!(imported_paths(src), ["utils.typ", "chapters/intro.typ"]);
+    }
+
+    #[test]
+    fn dependencies_follow_imports_inside_the_root() {
+        let base = std::env::temp_dir().join(format!(
+            "mdbook-typst-highlight-dependencies-{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&base);
+        let root = base.join("book");
+        fs::create_dir_all(root.join("lib")).unwrap();
+        fs::write(root.join("utils.typ"), "#import \"lib/colors.typ\": *").unwrap();
+        fs::write(
+            root.join("lib/colors.typ"),
+            "#let red = rgb(\"#f00\")\n#include \"../utils.typ\"\n#import \"/data.csv\"",
+        )
+        .unwrap();
+        fs::write(root.join("data.csv"), "a,b").unwrap();
+        fs::write(base.join("outside.typ"), "").unwrap();
+
+        let src = "#import \"utils.typ\": *\n#import \"missing.typ\"\n#import \"../outside.typ\"";
+        let found = dependencies(src, &root);
+        assert_eq!(
+            found.keys().collect::<Vec<_>>(),
+            ["data.csv", "lib/colors.typ", "utils.typ"]
+        );
+
+        // Edits of nested files change their hashes
+        fs::write(root.join("lib/colors.typ"), "#let red = rgb(\"#e00\")").unwrap();
+        let edited = dependencies(src, &root);
+        assert_ne!(found["lib/colors.typ"], edited["lib/colors.typ"]);
+        assert_eq!(found["utils.typ"], edited["utils.typ"]);
+        // `data.csv` was only imported by the old version
+        assert!(!edited.contains_key("data.csv"));
+
+        fs::remove_dir_all(base).unwrap();
+    }
+}
@@ -0,0 +1,756 @@
+//! Highlighting of code with syntect, see `highlight_typst`
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use anyhow::anyhow;
+use mdbook_preprocessor::errors::Result;
+use rayon::prelude::*;
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{Color, Theme, ThemeSet};
+use syntect::html::{
+    append_highlighted_html_for_styled_line, css_for_theme_with_class_style,
+    line_tokens_to_classed_spans, ClassStyle, IncludeBackground,
+};
+use syntect::parsing::{ParseState, Scope, ScopeStack, SyntaxReference, SyntaxSet};
+use syntect::util::LinesWithEndings;
+
+use crate::cache::sha256_hash;
+use crate::{
+    escape_html, placeholder, BlockAttrs, InlineWrapper, PreprocessSettings, DEFAULT_THEME_DATA,
+    HIGHLIGHT_PLACEHOLDER, SYNTAX,
+};
+
+/// Foreground of the default theme, which is replaced with a CSS variable in the highlighted HTML.
+/// The probability that the hack will break when you are writing colors is ≈ 1/(2⁸)⁴ ≈ 1/(2³²)
+/// In fact much less, very few people use alphas
+const SENTINEL_COLOR: Color = Color {
+    r: 27,
+    g: 223,
+    b: 51,
+    a: 99,
+};
+/// How syntect writes [`SENTINEL_COLOR`] into the HTML
+static SENTINEL_HEX: &str = "#1bdf3363";
+
+/// Shows only the variant of highlighted code that matches the mdBook theme
+pub(crate) static DUAL_THEMES_STYLE: &str = "<style>\
+.typst-hl-dark { display: none; }\
+.coal .typst-hl-dark, .navy .typst-hl-dark, .ayu .typst-hl-dark { display: revert; }\
+.coal .typst-hl-light, .navy .typst-hl-light, .ayu .typst-hl-light { display: none; }\
+</style>";
+
+/// Shows the copy buttons of the page, they stay hidden without JavaScript,
+/// which they need to work. Pages with `highlight_typst`'s copy buttons should include it after them
+pub static COPY_BUTTON_SCRIPT: &str = "<script>\
+document.querySelectorAll('.typst-copy').forEach((button) => button.style.display = '');\
+</script>";
+
+/// Themes to highlight with, either a single one
+/// or a pair that follows the light/dark mdBook theme
+#[allow(clippy::large_enum_variant)] // there is only one for the whole book
+pub(crate) enum Themes {
+    Single(Theme),
+    Dual { light: Theme, dark: Theme },
+}
+
+impl Themes {
+    pub(crate) fn load(settings: &PreprocessSettings) -> Result<Self> {
+        match (&settings.light_theme, &settings.dark_theme) {
+            (Some(light), Some(dark)) => Ok(Themes::Dual {
+                light: load_theme(Some(light), settings)?,
+                dark: load_theme(Some(dark), settings)?,
+            }),
+            (None, None) => match &settings.theme_file {
+                Some(path) => Ok(Themes::Single(load_theme_file(path)?)),
+                None => Ok(Themes::Single(load_theme(
+                    settings.theme.as_deref(),
+                    settings,
+                )?)),
+            },
+            _ => {
+                log::warn!("Both light_theme and dark_theme have to be set, ignoring them");
+                Ok(Themes::Single(load_theme(
+                    settings.theme.as_deref(),
+                    settings,
+                )?))
+            }
+        }
+    }
+
+    /// Highlighted `src` in `language` (`typ` for Typst), or the plain one if it can't be highlighted
+    fn highlight(
+        &self,
+        src: &str,
+        language: &str,
+        inline: bool,
+        emphasized: &BTreeSet<usize>,
+        chapter: &str,
+        settings: &PreprocessSettings,
+    ) -> String {
+        let html = match self {
+            Themes::Single(theme) => highlight(src, language, inline, emphasized, theme, settings),
+            Themes::Dual { light, dark } => {
+                let tag = if inline { "span" } else { "div" };
+                highlight(src, language, inline, emphasized, light, settings).and_then(|light| {
+                    Ok(format!(
+                        r#"<{tag} class="typst-hl-light">{light}</{tag}><{tag} class="typst-hl-dark">{}</{tag}>"#,
+                        highlight(src, language, inline, emphasized, dark, settings)?,
+                    ))
+                })
+            }
+        };
+
+        html.unwrap_or_else(|e| {
+            log::warn!(
+                "Can't highlight a snippet in chapter \"{chapter}\", it is shown as is: {e}"
+            );
+            plain_code(src, inline, settings)
+        })
+    }
+}
+
+/// A snippet to highlight, see `HighlightJobs`
+pub(crate) struct HighlightJob {
+    pub(crate) src: String,
+    /// `typ` for Typst
+    pub(crate) language: String,
+    pub(crate) inline: bool,
+    pub(crate) emphasized: BTreeSet<usize>,
+    pub(crate) themes: Arc<Themes>,
+    pub(crate) chapter: String,
+    /// Brackets are escaped, so that inline code in link text doesn't break the link
+    pub(crate) in_link: bool,
+    /// Classes of the `<pre>` from `class=`
+    pub(crate) pre_class: Option<String>,
+}
+
+impl HighlightJob {
+    pub(crate) fn new(src: String, themes: &Arc<Themes>, chapter: &str) -> Self {
+        HighlightJob {
+            src,
+            language: "typ".to_owned(),
+            inline: false,
+            emphasized: BTreeSet::new(),
+            themes: themes.clone(),
+            chapter: chapter.to_owned(),
+            in_link: false,
+            pre_class: None,
+        }
+    }
+}
+
+/// Snippets of the whole book, highlighted in parallel once all chapters are prepared.
+/// Chapters get placeholders meanwhile, see `placeholder`
+#[derive(Default)]
+pub(crate) struct HighlightJobs {
+    jobs: Vec<HighlightJob>,
+}
+
+impl HighlightJobs {
+    /// Placeholder of the highlighted snippet
+    pub(crate) fn push(&mut self, job: HighlightJob) -> String {
+        self.jobs.push(job);
+        placeholder(HIGHLIGHT_PLACEHOLDER, self.jobs.len() - 1)
+    }
+
+    /// Highlighted snippets by index
+    pub(crate) fn run(self, settings: &PreprocessSettings) -> Vec<String> {
+        self.jobs
+            .into_par_iter()
+            .map(|job| {
+                let html = job.themes.highlight(
+                    &job.src,
+                    &job.language,
+                    job.inline,
+                    &job.emphasized,
+                    &job.chapter,
+                    settings,
+                );
+                let html = match &job.pre_class {
+                    Some(class) => add_pre_class(&html, class),
+                    None => html,
+                };
+                if job.in_link {
+                    // Unbalanced brackets in the highlighted text would break the link
+                    html.replace('[', "&#91;").replace(']', "&#93;")
+                } else {
+                    html
+                }
+            })
+            .collect()
+    }
+}
+
+/// Highlighted blocks with `class` on their `<pre>`, both of them with dual themes
+pub(crate) fn add_pre_class(html: &str, class: &str) -> String {
+    html.replace(
+        "<pre style=",
+        &format!(r#"<pre class="{}" style="#, escape_html(class)),
+    )
+}
+
+/// Theme of a block with `theme_file=` (relative to `dir`, the chapter's directory) or `theme=`,
+/// unknown themes and broken files are ignored with a warning
+pub(crate) fn block_theme(
+    cache: &mut BTreeMap<String, Option<Arc<Themes>>>,
+    attrs: &BlockAttrs,
+    dir: &Path,
+    chapter: &str,
+    settings: &PreprocessSettings,
+) -> Option<Arc<Themes>> {
+    if let Some(file) = attrs.get("theme_file") {
+        let path = dir.join(file);
+        return cache
+            .entry(path.display().to_string())
+            .or_insert_with(|| match load_theme_file(&path) {
+                Ok(theme) => Some(Arc::new(Themes::Single(theme))),
+                Err(e) => {
+                    log::warn!(
+                        "Ignoring theme_file={file} of a block in chapter \"{chapter}\": {e}"
+                    );
+                    None
+                }
+            })
+            .clone();
+    }
+
+    let name = attrs.get("theme")?;
+    cache
+        .entry(name.to_owned())
+        .or_insert_with(|| match load_theme(Some(name), settings) {
+            Ok(theme) => Some(Arc::new(Themes::Single(theme))),
+            Err(e) => {
+                log::warn!("Ignoring theme={name} of a block in chapter \"{chapter}\": {e}");
+                None
+            }
+        })
+        .clone()
+}
+
+/// A `.tmTheme` file, used as it is
+fn load_theme_file(path: &Path) -> Result<Theme> {
+    ThemeSet::get_theme(path).map_err(|e| anyhow!("Can't load theme {}: {e}", path.display()))
+}
+
+fn load_theme(name: Option<&str>, settings: &PreprocessSettings) -> Result<Theme> {
+    let mut theme = theme(name.unwrap_or(settings.default_theme()))?;
+
+    if settings.foreground_hack() {
+        theme.settings.foreground = Some(SENTINEL_COLOR);
+    }
+
+    Ok(theme)
+}
+
+/// Highlights `src` the way the settings say, `emphasized` are 1-based numbers of lines to emphasize in blocks
+pub(crate) fn highlight(
+    src: &str,
+    language: &str,
+    inline: bool,
+    emphasized: &BTreeSet<usize>,
+    theme: &Theme,
+    settings: &PreprocessSettings,
+) -> Result<String, syntect::Error> {
+    let class = if inline {
+        settings.css_classes.inline()
+    } else {
+        settings.css_classes.block()
+    };
+
+    highlight_typst(
+        src,
+        &HighlightOptions {
+            theme,
+            syntax_set: settings.syntax_set(),
+            language,
+            inline,
+            // Themes from files aren't marked, they keep their own foreground
+            foreground: (theme.settings.foreground == Some(SENTINEL_COLOR))
+                .then(|| settings.fg_var()),
+            class: Some(class),
+            style_classes: settings
+                .highlight_classes
+                .then_some(&settings.style_classes),
+            inline_wrapper: settings.inline_wrapper,
+            line_numbers: settings.line_numbers,
+            wrap_lines: settings.wrap_lines,
+            bare: settings.bare_output,
+            copy_button: settings.copy_button,
+            emphasized,
+            max_line_length: settings.limits.max_line_length(inline),
+            include_background: settings.include_background,
+        },
+    )
+}
+
+/// Options of [`highlight_typst`], [`HighlightOptions::new`] gives the ones the preprocessor uses by default.
+/// More options may be added, so they are changed on a value made by `new` or `default`
+#[non_exhaustive]
+pub struct HighlightOptions<'a> {
+    pub theme: &'a Theme,
+    /// Syntaxes to choose from, [`syntax_set`] by default
+    pub syntax_set: &'a SyntaxSet,
+    /// Token of the syntax, such as a file extension, `typ` by default.
+    /// Unknown languages are shown as plain text
+    pub language: &'a str,
+    /// Whether `src` is inline code, a `<code>` element, rather than a `<pre>` block
+    pub inline: bool,
+    /// CSS value for the theme's foreground color, e.g. `var(--fg)`
+    pub foreground: Option<&'a str>,
+    /// Class of the `<code>` element, `nohighlight hljs` by default so highlight.js doesn't highlight it again
+    pub class: Option<&'a str>,
+    /// Code gets syntect's scope classes instead of `style` attributes, and these collect the themes' stylesheets
+    pub style_classes: Option<&'a StyleClasses>,
+    /// Element around inline code, blocks are always in `<pre><code>`
+    pub inline_wrapper: InlineWrapper,
+    pub line_numbers: bool,
+    /// Long lines of blocks are wrapped, numbers are still given to source lines only
+    pub wrap_lines: bool,
+    /// Blocks are the highlighted lines without `<pre><code>`, for containers styled elsewhere.
+    /// There is no copy button then
+    pub bare: bool,
+    /// Adds a button copying the source to the clipboard to blocks, hidden until `COPY_BUTTON_SCRIPT` shows it
+    pub copy_button: bool,
+    /// 1-based numbers of lines to emphasize in blocks
+    pub emphasized: &'a BTreeSet<usize>,
+    /// Longer lines are escaped, but not highlighted
+    pub max_line_length: Option<usize>,
+    /// Spans get the background colors of the theme, and blocks its background,
+    /// instead of those of the page
+    pub include_background: bool,
+}
+
+impl<'a> HighlightOptions<'a> {
+    pub fn new(theme: &'a Theme) -> Self {
+        static NO_LINES: BTreeSet<usize> = BTreeSet::new();
+
+        HighlightOptions {
+            theme,
+            syntax_set: &SYNTAX,
+            language: "typ",
+            inline: false,
+            foreground: None,
+            class: None,
+            style_classes: None,
+            inline_wrapper: InlineWrapper::Code,
+            line_numbers: false,
+            wrap_lines: false,
+            bare: false,
+            copy_button: false,
+            emphasized: &NO_LINES,
+            max_line_length: None,
+            include_background: false,
+        }
+    }
+
+    fn line_fits(&self, line: &str) -> bool {
+        self.max_line_length
+            .is_none_or(|max| line.chars().count() <= max)
+    }
+}
+
+/// Options of [`HighlightOptions::new`] with the default theme of the preprocessor, Solarized (dark)
+impl Default for HighlightOptions<'static> {
+    fn default() -> Self {
+        HighlightOptions::new(&DEFAULT_THEME_DATA)
+    }
+}
+
+/// `#rrggbb`, or `#rrggbbaa` for translucent colors, as syntect writes them
+fn css_color(color: Color) -> String {
+    let Color { r, g, b, a } = color;
+    if a == 0xff {
+        format!("#{r:02x}{g:02x}{b:02x}")
+    } else {
+        format!("#{r:02x}{g:02x}{b:02x}{a:02x}")
+    }
+}
+
+/// Syntect's default syntaxes and Typst
+pub fn syntax_set() -> &'static SyntaxSet {
+    &SYNTAX
+}
+
+/// One of the themes bundled with syntect, e.g. `Solarized (dark)`
+pub fn theme(name: &str) -> Result<Theme> {
+    let mut themes = ThemeSet::load_defaults().themes;
+
+    themes.remove(name).ok_or_else(|| {
+        anyhow!(
+            "Unknown theme \"{name}\", available themes are: {}",
+            themes.keys().cloned().collect::<Vec<_>>().join(", ")
+        )
+    })
+}
+
+/// Highlights Typst code into HTML, or code in another language of [`HighlightOptions::syntax_set`]
+pub fn highlight_typst(src: &str, opts: &HighlightOptions) -> Result<String, syntect::Error> {
+    let src = src.strip_suffix('\n').unwrap_or(src);
+
+    let syntaxes = opts.syntax_set;
+    let syntax = syntaxes
+        .find_syntax_by_token(opts.language)
+        .unwrap_or_else(|| syntaxes.find_syntax_plain_text());
+
+    // The stylesheet has the theme's colors, code gets the class of the theme
+    let theme_class = opts
+        .style_classes
+        .map(|classes| classes.theme_class(opts.theme, opts.foreground, opts.include_background))
+        .transpose()?;
+    let class = match &theme_class {
+        Some(theme_class) => format!("{} {theme_class}", opts.class.unwrap_or("nohighlight hljs")),
+        None => opts.class.unwrap_or("nohighlight hljs").to_owned(),
+    };
+
+    // The foreground is marked to be found in the HTML, themes of the preprocessor already are
+    let marked;
+    let theme = match opts.foreground {
+        Some(_)
+            if theme_class.is_none() && opts.theme.settings.foreground != Some(SENTINEL_COLOR) =>
+        {
+            let mut theme = opts.theme.clone();
+            theme.settings.foreground = Some(SENTINEL_COLOR);
+            marked = theme;
+            &marked
+        }
+        _ => opts.theme,
+    };
+    let background = if opts.include_background {
+        IncludeBackground::Yes
+    } else {
+        IncludeBackground::No
+    };
+    let mut html = if opts.inline && !opts.line_fits(src) {
+        opts.inline_wrapper.wrap(&class, &escape_html(src))
+    } else if opts.inline {
+        let mut html = String::new();
+        LineHighlighter::new(syntax, theme, background, theme_class.is_some())
+            .append(src, syntaxes, &mut html)?;
+        match (&theme_class, opts.inline_wrapper) {
+            // The rules of the theme need an element of its class around the spans
+            (Some(theme_class), InlineWrapper::None) => {
+                format!(r#"<span class="{theme_class}">{html}</span>"#)
+            }
+            _ => opts.inline_wrapper.wrap(&class, &html),
+        }
+    } else {
+        let position = if opts.copy_button {
+            "; position: relative"
+        } else {
+            ""
+        };
+        let wrap = if opts.wrap_lines {
+            "; white-space: pre-wrap; word-break: break-word"
+        } else {
+            ""
+        };
+        // Otherwise the gaps between the spans would have the page background
+        let code_style = match theme.settings.background {
+            Some(color) if opts.include_background => {
+                format!(r#" style="background: {}""#, css_color(color))
+            }
+            _ => String::new(),
+        };
+        let mut html = match &theme_class {
+            Some(theme_class) if opts.bare => format!(r#"<span class="{theme_class}">"#),
+            _ if opts.bare => String::new(),
+            _ => format!(
+                r#"<pre style="margin: 0{position}{wrap}"><code class="{}"{code_style}>"#,
+                escape_html(&class)
+            ),
+        };
+
+        let mut highlighter =
+            LineHighlighter::new(syntax, theme, background, theme_class.is_some());
+
+        let digits = src.lines().count().max(1).to_string().len();
+
+        for (n, line) in LinesWithEndings::from(src).enumerate() {
+            let mut line_html = String::new();
+
+            if opts.line_numbers {
+                // Not selectable, so that copied code stays clean
+                line_html.push_str(&format!(
+                    r#"<span class="typst-lineno" style="display: inline-block; width: {digits}ch; margin-right: 1em; text-align: right; opacity: 0.5; user-select: none;">{}</span>"#,
+                    n + 1
+                ));
+            }
+            if opts.line_fits(line) {
+                highlighter.append(line, syntaxes, &mut line_html)?;
+            } else {
+                line_html.push_str(&escape_html(line));
+            }
+
+            if opts.emphasized.contains(&(n + 1)) {
+                // The line break goes after the wrapper, otherwise it would be two lines high
+                let ends_with_newline = line.ends_with('\n');
+                if ends_with_newline {
+                    line_html = line_html.replacen('\n', "", 1);
+                }
+                html.push_str(&format!(
+                    r#"<span class="highlighted-line" style="display: inline-block; width: 100%; background: rgba(127, 127, 127, 0.2);">{line_html}</span>"#
+                ));
+                if ends_with_newline {
+                    html.push('\n');
+                }
+            } else {
+                html.push_str(&line_html);
+            }
+        }
+
+        if !opts.bare {
+            html.push_str("</code>");
+
+            if opts.copy_button {
+                html.push_str(&format!(
+                    r#"<button class="clip-button typst-copy" title="Copy to clipboard" aria-label="Copy to clipboard" style="display: none; position: absolute; top: 0.3em; right: 0.3em;" data-source="{}" onclick="navigator.clipboard.writeText(this.dataset.source)"><i class="tooltiptext"></i></button>"#,
+                    escape_html(src)
+                ));
+            }
+
+            html.push_str("</pre>");
+        } else if theme_class.is_some() {
+            html.push_str("</span>");
+        }
+
+        html
+    };
+
+    if let Some(foreground) = opts.foreground {
+        // It ends up in `style` attributes. Only the foreground is marked,
+        // so backgrounds of `include_background` are never replaced
+        html = html.replace(SENTINEL_HEX, &escape_html(foreground));
+    }
+
+    Ok(html)
+}
+
+/// Prefix of syntect's scope classes, `source.typst` is `typst-source typst-typst`
+const CLASS_STYLE: ClassStyle = ClassStyle::SpacedPrefixed { prefix: "typst-" };
+
+/// Highlights code line by line, into `style` attributes or scope classes
+enum LineHighlighter<'a> {
+    Styled(HighlightLines<'a>, IncludeBackground),
+    /// What syntect's `ClassedHTMLGenerator` does, but each line closes its spans
+    /// and the next one opens them again, so lines can be wrapped on their own
+    Classed(ParseState, ScopeStack),
+}
+
+impl<'a> LineHighlighter<'a> {
+    fn new(
+        syntax: &SyntaxReference,
+        theme: &'a Theme,
+        background: IncludeBackground,
+        classed: bool,
+    ) -> Self {
+        if classed {
+            LineHighlighter::Classed(ParseState::new(syntax), ScopeStack::new())
+        } else {
+            LineHighlighter::Styled(HighlightLines::new(syntax, theme), background)
+        }
+    }
+
+    fn append(
+        &mut self,
+        line: &str,
+        syntaxes: &SyntaxSet,
+        html: &mut String,
+    ) -> Result<(), syntect::Error> {
+        match self {
+            LineHighlighter::Styled(highlighter, background) => {
+                let regions = highlighter.highlight_line(line, syntaxes)?;
+                append_highlighted_html_for_styled_line(&regions[..], *background, html)
+            }
+            LineHighlighter::Classed(state, stack) => {
+                for scope in stack.as_slice() {
+                    html.push_str(&format!(r#"<span class="{}">"#, scope_classes(*scope)));
+                }
+                let ops = state.parse_line(line, syntaxes)?;
+                let (spans, _) = line_tokens_to_classed_spans(line, &ops, CLASS_STYLE, stack)?;
+                html.push_str(&spans);
+                html.push_str(&"</span>".repeat(stack.len()));
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Classes of a scope the way syntect gives them with [`CLASS_STYLE`]
+fn scope_classes(scope: Scope) -> String {
+    let ClassStyle::SpacedPrefixed { prefix } = CLASS_STYLE else {
+        unreachable!()
+    };
+    scope
+        .build_string()
+        .split('.')
+        .map(|atom| escape_html(&format!("{prefix}{atom}")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// [`highlight_typst`] with its options and a hook for the finished HTML, for library users:
+///
+/// ```
+/// use mdbook_typst_highlight::{theme, Highlighter};
+///
+/// let theme = theme("InspiredGitHub")?;
+/// let highlighter = Highlighter::new(&theme)
+///     .line_numbers(true)
+///     .post_process(|html| html.replacen("<pre", r#"<pre role="region""#, 1));
+/// let html = highlighter.highlight("#let x = 1")?;
+/// assert!(html.starts_with(r#"<pre role="region""#));
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub struct Highlighter<'a> {
+    options: HighlightOptions<'a>,
+    post_process: Option<Box<dyn Fn(String) -> String + 'a>>,
+}
+
+impl<'a> Highlighter<'a> {
+    /// Highlighter with the options of [`HighlightOptions::new`]
+    pub fn new(theme: &'a Theme) -> Self {
+        Self::with_options(HighlightOptions::new(theme))
+    }
+
+    pub fn with_options(options: HighlightOptions<'a>) -> Self {
+        Highlighter {
+            options,
+            post_process: None,
+        }
+    }
+
+    /// Token of the syntax, see [`HighlightOptions::language`]
+    pub fn language(mut self, language: &'a str) -> Self {
+        self.options.language = language;
+        self
+    }
+
+    /// Highlights inline code rather than blocks
+    pub fn inline(mut self, inline: bool) -> Self {
+        self.options.inline = inline;
+        self
+    }
+
+    pub fn line_numbers(mut self, line_numbers: bool) -> Self {
+        self.options.line_numbers = line_numbers;
+        self
+    }
+
+    /// Transforms the finished HTML, e.g. to add ARIA roles. It runs last,
+    /// the preprocessor doesn't set it
+    pub fn post_process(mut self, hook: impl Fn(String) -> String + 'a) -> Self {
+        self.post_process = Some(Box::new(hook));
+        self
+    }
+
+    /// The other options, to change what the builder methods don't cover
+    pub fn options_mut(&mut self) -> &mut HighlightOptions<'a> {
+        &mut self.options
+    }
+
+    pub fn highlight(&self, src: &str) -> Result<String, syntect::Error> {
+        let html = highlight_typst(src, &self.options)?;
+        Ok(match &self.post_process {
+            Some(post_process) => post_process(html),
+            None => html,
+        })
+    }
+}
+
+/// Stylesheets of the themes of code highlighted with scope classes. Each theme's rules,
+/// made by syntect, apply inside elements of its class `typst-theme-<hash>`
+#[derive(Default)]
+pub struct StyleClasses {
+    /// Class of a theme → its rules
+    themes: Mutex<BTreeMap<String, String>>,
+}
+
+impl StyleClasses {
+    /// Class of the element around code highlighted with `theme`. The class comes from the rules,
+    /// so it doesn't depend on the order in which code is highlighted
+    fn theme_class(
+        &self,
+        theme: &Theme,
+        foreground: Option<&str>,
+        include_background: bool,
+    ) -> Result<String, syntect::Error> {
+        // The base rule is replaced with one for the element of the theme's class
+        const BASE: &str = ".typst-code";
+        const CLASS: &str = "{class}";
+
+        let color = foreground
+            .map(str::to_owned)
+            .or_else(|| theme.settings.foreground.map(css_color));
+        let background = theme
+            .settings
+            .background
+            .filter(|_| include_background)
+            .map(css_color);
+        let mut rules = format!(
+            ".{CLASS} {{ {}{}}}\n",
+            color.map_or(String::new(), |color| format!("color: {color}; ")),
+            background.map_or(String::new(), |bg| format!("background-color: {bg}; ")),
+        );
+
+        let css = css_for_theme_with_class_style(theme, CLASS_STYLE)?;
+        let css = css
+            .split_once("*/\n")
+            .map_or(css.as_str(), |(_, rules)| rules);
+        for rule in css.split_terminator("}\n") {
+            let Some((selectors, body)) = rule.trim().split_once(" {\n") else {
+                continue;
+            };
+            let declarations = body
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .filter(|line| include_background || !line.starts_with("background-color"))
+                .collect::<Vec<_>>();
+            if selectors == BASE || declarations.is_empty() {
+                continue;
+            }
+            let selectors = selectors
+                .split(", ")
+                .map(|selector| format!(".{CLASS} {selector}"))
+                .collect::<Vec<_>>();
+            rules.push_str(&format!(
+                "{} {{ {} }}\n",
+                selectors.join(", "),
+                declarations.join(" ")
+            ));
+        }
+
+        let class = format!("typst-theme-{}", &sha256_hash(&rules)[..8]);
+        self.themes
+            .lock()
+            .unwrap()
+            .entry(class.clone())
+            .or_insert_with(|| rules.replace(CLASS, &class));
+        Ok(class)
+    }
+
+    /// Stylesheet of the themes used so far
+    pub fn css(&self) -> String {
+        self.themes.lock().unwrap().values().cloned().collect()
+    }
+}
+
+/// Code as it is, for when it can't be highlighted
+pub(crate) fn plain_code(src: &str, inline: bool, settings: &PreprocessSettings) -> String {
+    let src = escape_html(src.strip_suffix('\n').unwrap_or(src));
+
+    if inline {
+        settings
+            .inline_wrapper
+            .wrap(settings.css_classes.inline(), &src)
+    } else if settings.bare_output {
+        src
+    } else {
+        format!(
+            r#"<pre style="margin: 0"><code class="{}">{src}</code></pre>"#,
+            escape_html(settings.css_classes.block())
+        )
+    }
+}
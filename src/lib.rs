@@ -1,377 +1,5715 @@
-use async_process::Command;
-use futures::future::join_all;
+use futures::future::{self, FutureExt, LocalBoxFuture};
+use futures::stream::{self, StreamExt};
 use sha2::{Digest, Sha256};
-use std::fs::{self, File};
-use std::future::Future;
-use std::io::Write;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsString;
+use std::fs;
+use std::hash::BuildHasher;
 use std::iter;
+use std::ops::{Deref, RangeInclusive};
 use std::path::{Path, PathBuf};
-use std::str::FromStr;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::anyhow;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
 use lazy_static::lazy_static;
 use mdbook_markdown::new_cmark_parser;
-use mdbook_markdown::pulldown_cmark::{CodeBlockKind, Event, Tag, TagEnd};
+use mdbook_markdown::pulldown_cmark::{CodeBlockKind, Event, LinkType, Tag, TagEnd};
 use mdbook_preprocessor::book::{Book, Chapter};
 use mdbook_preprocessor::errors::{Error, Result};
 use mdbook_preprocessor::{Preprocessor, PreprocessorContext};
 use pulldown_cmark_to_cmark::cmark;
-use serde::Deserialize;
-use syntect::highlighting::Color;
-use syntect::parsing::SyntaxSet;
-
-use syntect::easy::HighlightLines;
-use syntect::highlighting::{Theme, ThemeSet};
-use syntect::html::{
-    append_highlighted_html_for_styled_line, styled_line_to_highlighted_html, IncludeBackground,
+use serde::de::IgnoredAny;
+use serde::{Deserialize, Serialize};
+use syntect::parsing::syntax_definition::SyntaxDefinition;
+pub use syntect::parsing::SyntaxSet;
+
+use syntect::dumps::from_binary;
+pub use syntect::highlighting::Theme;
+
+mod cache;
+mod highlight;
+mod render;
+
+pub use highlight::{
+    highlight_typst, syntax_set, theme, HighlightOptions, Highlighter, StyleClasses,
+    COPY_BUTTON_SCRIPT,
+};
+
+use cache::{
+    parse_version, prune_cache, record_typst_version, recorded_typst_version, sha256_hash,
+    split_cache_name, typst_version, Manifests,
+};
+use highlight::{
+    add_pre_class, block_theme, plain_code, HighlightJob, HighlightJobs, Themes, DUAL_THEMES_STYLE,
+};
+use render::{
+    check_golden, get_images, image_size, render_block, svg_length, BlockLocation, Progress,
+    RenderRequest,
 };
-use syntect::util::LinesWithEndings;
 
 static PREAMBLE: &str = "#set page(height: auto, width: 400pt, margin: 0.5cm)\n";
 
-lazy_static! {
-    static ref THEME: Theme = {
-        let ts = ThemeSet::load_defaults();
-        let mut theme = ts.themes["Solarized (dark)"].clone();
-        theme.settings.foreground = Some(Color {
-            r: 27,
-            g: 223,
-            b: 51,
-            a: 99,
-        });
-        // The probability that the hack will break when you are writing colors is ≈ 1/(2⁸)⁴ ≈ 1/(2³²)
-        // In fact much less, very few people use alphas
+/// What `filename_scheme` can be made of, besides plain text
+static FILENAME_PLACEHOLDERS: [&str; 3] = ["{chapter}", "{caption}", "{n}"];
 
-        theme
-    };
+/// Length of the hash after the readable part of names from `filename_scheme`
+const SHORT_HASH_LEN: usize = 16;
+
+/// Longest readable part of names from `filename_scheme`, in characters
+const MAX_SLUG_LEN: usize = 48;
+
+/// Page of `typm` blocks, fitting the formula
+static MATH_PAGE: &str = "#set page(width: auto, height: auto, margin: 2pt)\n";
+
+static DEFAULT_THEME: &str = "Solarized (dark)";
+
+/// Highlighting themes for mdBook's built-in themes, used when `theme` isn't set
+static BOOK_THEMES: [(&str, &str); 5] = [
+    ("light", "Solarized (light)"),
+    ("rust", "Solarized (light)"),
+    ("coal", "Solarized (dark)"),
+    ("navy", "Solarized (dark)"),
+    ("ayu", "Solarized (dark)"),
+];
+
+/// Typst version whose syntax `res/Typst.sublime-syntax` covers, newer syntax such as
+/// `context` may be highlighted incorrectly
+pub const SYNTAX_TYPST_VERSION: &str = "0.10.0";
+
+/// Renderer of print editions, see `print_renderers` for others
+const PRINT_RENDERER: &str = "typst-pdf";
 
-    static ref SYNTAX: SyntaxSet = {
-        let typst_syntax = syntect::parsing::syntax_definition::SyntaxDefinition::load_from_str(
-            include_str!("../res/Typst.sublime-syntax"),
-            true,
-            None,
-        ).expect("Syntax data was corrupted");
+static DEFAULT_FG_VAR: &str = "var(--fg)";
 
-        let mut syntax = SyntaxSet::load_defaults_nonewlines().into_builder();
-        syntax.add(typst_syntax);
-        syntax.build()
+static DEFAULT_WRAPPER_BG: &str = "var(--quote-bg)";
+
+lazy_static! {
+    /// Syntect's default syntaxes with Typst, built by `build.rs`
+    static ref SYNTAX: SyntaxSet =
+        from_binary(include_bytes!(concat!(env!("OUT_DIR"), "/syntaxes.packdump")));
+
+    /// Theme of `HighlightOptions::default`
+    static ref DEFAULT_THEME_DATA: Theme = theme(DEFAULT_THEME).unwrap();
+
+    /// Part of the placeholders of the run, see `placeholder`
+    static ref PLACEHOLDER_NONCE: String = {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let seed = RandomState::new().hash_one(time);
+        sha256_hash(&format!("{}-{time}-{seed}", std::process::id()))[..SHORT_HASH_LEN].to_owned()
     };
 }
 
+/// Name of the placeholders of highlighted snippets, see `HighlightJobs`
+const HIGHLIGHT_PLACEHOLDER: &str = "typst-highlight";
+/// Name of the placeholders of rendered images in HTML
+const IMAGE_PLACEHOLDER: &str = "typst-render-insert-image";
+/// Name of the placeholders of rendered images as Markdown, see `markdown_figures`
+const MARKDOWN_PLACEHOLDER: &str = "typst-render-insert-markdown";
+
 pub struct TypstHighlight;
 
+/// The preprocessor's table of `book.toml`
 #[derive(Deserialize, Default)]
-struct PreprocessSettings {
+struct PreprocessConfig {
     #[serde(default)]
     disable_inline: bool,
     #[serde(default)]
     typst_default: bool,
+    /// Like `typst_default`, for indented blocks
+    #[serde(default)]
+    indented_default: bool,
     #[serde(default)]
     render: bool,
     #[serde(default)]
     warn_not_specified: bool,
+    #[serde(default)]
+    inline_in_links: InlineInLinks,
+    #[serde(default)]
+    inline_wrapper: InlineWrapper,
+    #[serde(default)]
+    block_spacing: BlockSpacing,
+    #[serde(default)]
+    line_numbers: bool,
+    /// Wrap long lines of blocks instead of scrolling them
+    #[serde(default)]
+    wrap_lines: bool,
+    /// Blocks are only the highlighted lines, without `<pre><code>` around them
+    #[serde(default)]
+    bare_output: bool,
+    #[serde(default)]
+    copy_button: bool,
+    /// Renderers of print editions besides `typst-pdf`, they get PNG images and PDFs
+    #[serde(default)]
+    print_renderers: Vec<String>,
+    #[serde(default)]
+    css_classes: CssClasses,
+    /// Only inline code starting with it is highlighted
+    #[serde(default)]
+    inline_prefix: Option<String>,
+    #[serde(default)]
+    thumbnail: bool,
+    #[serde(default)]
+    thumbnail_size: Option<f64>,
+    #[serde(default)]
+    transparent_background: bool,
+    /// CSS length, how wide rendered images may get
+    #[serde(default)]
+    max_width: Option<String>,
+    /// Factor applied to the typeset size of rendered images
+    #[serde(default)]
+    scale: Option<f64>,
+    /// Horizontal alignment of rendered images in their box
+    #[serde(default)]
+    align: ImageAlign,
+    /// CSS `vertical-align` of rendered `typm` formulas
+    #[serde(default)]
+    math_vertical_align: Option<String>,
+    #[serde(default)]
+    disable_foreground_hack: bool,
+    #[serde(default)]
+    fg_var: Option<String>,
+    /// CSS background of the boxes around rendered images
+    #[serde(default)]
+    wrapper_bg: Option<String>,
+    #[serde(default)]
+    theme: Option<String>,
+    /// `.tmTheme` file used instead of `theme`, relative to the book root
+    #[serde(default)]
+    theme_file: Option<PathBuf>,
+    /// Overrides of `BOOK_THEMES`, mdBook theme to highlighting theme
+    #[serde(default)]
+    theme_map: BTreeMap<String, String>,
+    /// Highlighting styles go to a stylesheet instead of every `<span>`
+    #[serde(default)]
+    highlight_classes: bool,
+    /// Where the stylesheet of `highlight_classes` goes, relative to the book root,
+    /// it is added to every chapter otherwise
+    #[serde(default)]
+    highlight_css: Option<PathBuf>,
+    #[serde(default)]
+    light_theme: Option<String>,
+    #[serde(default)]
+    dark_theme: Option<String>,
+    /// Highlight with the theme's background colors instead of the page background
+    #[serde(default)]
+    include_background: bool,
+    /// Replaces `PREAMBLE`, prepended verbatim to rendered blocks
+    #[serde(default)]
+    preamble: Option<String>,
+    /// Page of rendered blocks, composed into `#set page(...)` instead of the one of `PREAMBLE`
+    #[serde(default)]
+    page_width: Option<String>,
+    #[serde(default)]
+    page_height: Option<String>,
+    #[serde(default)]
+    margin: Option<String>,
+    /// Remove blank lines at the end of blocks
+    #[serde(default)]
+    trim_trailing: Option<bool>,
+    /// `false` makes every block `nopreamble`, the book sets up pages itself
+    #[serde(default)]
+    preamble_enabled: Option<bool>,
+    /// Show the preamble of rendered blocks above their code
+    #[serde(default)]
+    show_preamble_in_source: bool,
+    #[serde(default)]
+    render_par_justify: Option<bool>,
+    #[serde(default)]
+    render_par_leading: Option<String>,
+    #[serde(default)]
+    render_par_spacing: Option<String>,
+    /// Size of text in rendered blocks, composed into `#set text(size: ...)`
+    #[serde(default)]
+    render_text_size: Option<String>,
+    /// Directory with reference images, relative to the book root
+    #[serde(default)]
+    golden: Option<PathBuf>,
+    /// Globs of chapters whose blocks are rendered, all chapters if empty
+    #[serde(default)]
+    render_include: Vec<String>,
+    /// Globs of chapters whose blocks are only highlighted, wins over `render_include`
+    #[serde(default)]
+    render_exclude: Vec<String>,
+    /// Write a list of rendered blocks for other tools
+    #[serde(default)]
+    emit_index: bool,
+    /// Where the list goes, relative to the book root, `typst-index.json` by default
+    #[serde(default)]
+    index_path: Option<PathBuf>,
+    /// Only report which blocks would be compiled, without running Typst
+    #[serde(default)]
+    dry_run: bool,
+    /// Compile every block again, overwriting the cache. Also set by `TYPST_HIGHLIGHT_FORCE=1`
+    #[serde(default)]
+    force_render: bool,
+    #[serde(default)]
+    golden_threshold: f64,
+    #[serde(default)]
+    golden_update: bool,
+    #[serde(default)]
+    unicode_normalization: UnicodeNormalization,
+    #[serde(default)]
+    markdown_figures: bool,
+    #[serde(default)]
+    inline_svg: bool,
+    /// `loading="lazy"` and the size of images, so that pages don't jump while they load
+    #[serde(default)]
+    lazy_loading: Option<bool>,
+    #[serde(default)]
+    optimize_svg: bool,
+    #[serde(default)]
+    single_page: bool,
+    #[serde(default)]
+    source_download: bool,
+    /// The compiled source of rendered blocks in `data-typst-src`, for scripts of the book
+    #[serde(default)]
+    embed_source: bool,
+    #[serde(default)]
+    pdf: bool,
+    /// Formats besides `format` that blocks are also compiled to, for downloading
+    #[serde(default)]
+    formats: Vec<DownloadFormat>,
+    /// Show images as `<picture>` of the SVG with the PNG as a fallback, both have to be rendered
+    #[serde(default)]
+    picture: bool,
+    /// Remove cached files that are no longer used
+    #[serde(default)]
+    prune_cache: bool,
+    /// Fail the build on Typst errors instead of just reporting them
+    #[serde(default)]
+    strict: bool,
+    /// How many times a render that failed to download a package is tried again
+    #[serde(default)]
+    render_retries: u32,
+    /// Renders that fail to download a package only give a warning, e.g. in CI without network
+    #[serde(default)]
+    skip_render_on_offline: bool,
+    /// Show errors of rendered blocks under them in the book, besides the log
+    #[serde(default)]
+    show_errors_inline: bool,
+    /// Log every rendered block, not only the summary
+    #[serde(default)]
+    verbose: bool,
+    #[serde(default)]
+    limits: Limits,
+    #[serde(default)]
+    format: ImageFormat,
+    /// Resolution of PNG images
+    #[serde(default)]
+    ppi: Option<f64>,
+    /// Also render PNG images at twice the `ppi` for hi-DPI screens
+    #[serde(default)]
+    srcset: bool,
+    /// Where rendered files are kept instead of next to chapters, relative to the book root
+    #[serde(default)]
+    out_dir: Option<PathBuf>,
+    /// Keep rendered files of all chapters in one directory, so that identical blocks are shared
+    #[serde(default)]
+    shared_cache: bool,
+    /// Readable names of rendered files, e.g. `{chapter}-{caption}`, followed by a short hash
+    #[serde(default)]
+    filename_scheme: Option<String>,
+    /// Typst executable, found in PATH by default
+    #[serde(default)]
+    typst_path: Option<PathBuf>,
+    /// Local packages, `--package-path`
+    #[serde(default)]
+    package_path: Option<PathBuf>,
+    /// Downloaded packages, `--package-cache-path`
+    #[serde(default)]
+    package_cache_path: Option<PathBuf>,
+    /// `--root` of every block instead of its chapter's directory, relative to the book root
+    #[serde(default)]
+    typst_root: Option<PathBuf>,
+    /// Font directories besides `src/fonts`, relative to the book root
+    #[serde(default)]
+    font_paths: Vec<PathBuf>,
+    /// Fonts of `src/fonts` and `font_paths` are given to inline SVGs as WOFF2 `@font-face` rules
+    #[serde(default)]
+    embed_fonts: bool,
+    /// `--ignore-system-fonts`, so that images don't depend on fonts of the machine
+    #[serde(default)]
+    ignore_system_fonts: bool,
+    /// `.sublime-syntax` files for highlighting blocks in other languages, relative to the book root
+    #[serde(default)]
+    extra_syntaxes: Vec<PathBuf>,
+    /// Load only the Typst syntax instead of syntect's defaults, unless there are `extra_syntaxes`
+    #[serde(default)]
+    minimal_syntaxes: bool,
+    /// `sys.inputs` of every block, `--input`
+    #[serde(default)]
+    inputs: BTreeMap<String, String>,
+    /// Experimental Typst features, `--features`
+    #[serde(default)]
+    features: Vec<String>,
+    /// Passed to `typst c` as is, before the output path
+    #[serde(default)]
+    extra_args: Vec<String>,
+    /// Keys that aren't settings, such as typos, they are reported in `run`
+    #[serde(flatten)]
+    unknown: BTreeMap<String, IgnoredAny>,
 }
 
-impl PreprocessSettings {
-    #[inline(always)]
-    fn highlight_inline(&self) -> bool {
-        !self.disable_inline
-    }
+/// `PreprocessConfig` resolved for a run, with what was found out about the book and Typst
+struct PreprocessSettings {
+    config: PreprocessConfig,
+    /// Highlighting theme matching the default mdBook theme
+    book_theme: Option<String>,
+    /// Classes of the styles seen so far, see `highlight_classes`
+    style_classes: StyleClasses,
+    /// The book sources, pages are relative to them
+    src_dir: PathBuf,
+    /// `out_dir` relative to the book sources, or to the HTML output if it is outside of them
+    out_dir_url: Option<String>,
+    /// Where rendered files are kept, the book sources unless `out_dir` is set or they are read-only
+    cache_dir: PathBuf,
+    /// Set when the book sources are read-only and rendered files are kept elsewhere
+    embed_assets: bool,
+    /// `font_paths` resolved against the book root
+    font_dirs: Vec<PathBuf>,
+    /// Fonts converted for `embed_fonts`
+    font_faces: Vec<FontFace>,
+    /// Syntect's and Typst syntaxes with the extra ones, if there are any
+    syntax_set: Option<SyntaxSet>,
+    /// File extensions of the extra syntaxes, blocks in these languages are highlighted
+    extra_languages: BTreeSet<String>,
+    /// Output of `typst --version`, images are cached per compiler version
+    typst_version: String,
 }
 
-impl Preprocessor for TypstHighlight {
-    fn name(&self) -> &str {
-        "typst-highlight"
+impl Deref for PreprocessSettings {
+    type Target = PreprocessConfig;
+
+    fn deref(&self) -> &PreprocessConfig {
+        &self.config
     }
+}
 
-    fn run(&self, ctx: &PreprocessorContext, mut book: Book) -> Result<Book, Error> {
-        let settings = ctx
-            .config
-            .get::<PreprocessSettings>("preprocessor.typst-highlight")?
-            .unwrap_or_default();
+/// Keys of the preprocessor's table that mdBook reads itself
+const MDBOOK_KEYS: [&str; 5] = ["command", "renderers", "before", "after", "optional"];
 
-        let mut errors = vec![];
+/// Where to normalize snippets to NFC, so that the same text typed
+/// in different editors is cached under the same name
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum UnicodeNormalization {
+    /// Keep snippets as they are
+    None,
+    /// Normalize only when computing the cache name
+    #[default]
+    Hash,
+    /// Also pass the normalized source to Typst
+    Source,
+}
 
-        book.for_each_chapter_mut(|chapter| {
-            let mut build_dir = ctx.root.clone();
-            build_dir.push(&ctx.config.book.src);
+/// Resource limits, `[preprocessor.typst-highlight.limits]`
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct Limits {
+    /// Seconds a single `typst` invocation may take
+    #[serde(default)]
+    render_timeout_secs: Option<u64>,
+    /// How many `typst` processes may run at once, the limit is shared by the whole book
+    #[serde(default)]
+    max_parallel: Option<usize>,
+    /// Larger snippets are highlighted, but not rendered
+    #[serde(default)]
+    max_snippet_bytes: Option<usize>,
+    /// Longer lines are not highlighted
+    #[serde(default)]
+    max_line_length: Option<usize>,
+    /// Longer inline code is not highlighted, so that a huge span can't slow the build down
+    #[serde(default)]
+    max_inline_length: Option<usize>,
+    /// Larger freshly rendered images get a warning, or an error in strict mode
+    #[serde(default)]
+    max_image_bytes: Option<u64>,
+}
 
-            if let Err(e) = process_chapter(chapter, &settings, &build_dir) {
-                errors.push(e);
-            }
-        });
+impl Limits {
+    fn validate(&self) -> Result<()> {
+        let zero = [
+            ("render_timeout_secs", self.render_timeout_secs == Some(0)),
+            ("max_parallel", self.max_parallel == Some(0)),
+            ("max_snippet_bytes", self.max_snippet_bytes == Some(0)),
+            ("max_line_length", self.max_line_length == Some(0)),
+            ("max_inline_length", self.max_inline_length == Some(0)),
+            ("max_image_bytes", self.max_image_bytes == Some(0)),
+        ];
 
-        if errors.is_empty() {
-            Ok(book)
+        match zero.iter().find(|(_, is_zero)| *is_zero) {
+            Some((name, _)) => Err(anyhow!(
+                "preprocessor.typst-highlight.limits.{name} must be greater than zero"
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Longest highlighted line, inline code has a limit of its own
+    fn max_line_length(&self, inline: bool) -> Option<usize> {
+        if inline {
+            let max_inline = self.max_inline_length.unwrap_or(2000);
+            Some(
+                self.max_line_length
+                    .map_or(max_inline, |max| max.min(max_inline)),
+            )
         } else {
-            Err(anyhow!(
-                "Errors occurred during preprocessing:\n{:#?}",
-                errors
-            ))
+            self.max_line_length
         }
     }
 
-    fn supports_renderer(&self, renderer: &str) -> Result<bool> {
-        Ok(renderer == "html")
+    #[inline(always)]
+    fn render_timeout(&self) -> Duration {
+        Duration::from_secs(self.render_timeout_secs.unwrap_or(60))
     }
-}
 
-fn process_chapter(
-    chapter: &mut Chapter,
-    settings: &PreprocessSettings,
-    build_dir: &Path,
-) -> Result<()> {
-    let events = new_cmark_parser(&chapter.content, &Default::default());
-    let mut new_events = Vec::new();
+    fn max_parallel(&self) -> usize {
+        self.max_parallel
+            .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()))
+    }
 
-    // (lang, text) of the current codeblock
-    let mut current_codeblock: Option<(String, String)> = None;
+    #[inline(always)]
+    fn snippet_fits(&self, src: &str) -> bool {
+        self.max_snippet_bytes.is_none_or(|max| src.len() <= max)
+    }
+}
 
-    let mut chapter_path = build_dir.to_path_buf();
-    if let Some(p) = chapter.path.as_ref().and_then(|p| p.parent()) {
-        chapter_path.push(p)
-    };
+/// Classes of the generated `<code>` elements, `[preprocessor.typst-highlight.css_classes]`
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct CssClasses {
+    #[serde(default)]
+    block: Option<String>,
+    #[serde(default)]
+    inline: Option<String>,
+}
 
-    let mut compile_errors = vec![];
+impl CssClasses {
+    #[inline(always)]
+    fn block(&self) -> &str {
+        self.block.as_deref().unwrap_or("nohighlight hljs")
+    }
 
-    for event in events {
-        match event {
-            Event::Start(Tag::CodeBlock(ref kind)) => {
-                match codeblock_lang(kind, settings, chapter.name.as_str()) {
-                    Some(lang) if is_typst_codeblock(lang) => {
-                        current_codeblock = Some((lang.to_owned(), String::new()))
-                    }
-                    _ => new_events.push(event),
-                }
-            }
-            Event::End(TagEnd::CodeBlock) => match current_codeblock {
-                Some((lang, text)) => {
-                    let mut html = highlight(text.as_str(), false);
-
-                    if settings.render && !lang.contains("norender") {
-                        let (file, err) = render_block(
-                            text,
-                            chapter_path.clone(),
-                            build_dir.to_path_buf(),
-                            chapter.name.clone(),
-                            !lang.contains("nopreamble"),
-                        );
-                        let file = file.to_str().unwrap();
+    #[inline(always)]
+    fn inline(&self) -> &str {
+        self.inline.as_deref().unwrap_or("nohighlight hljs")
+    }
+}
 
-                        compile_errors.extend(err);
+/// Format of rendered images
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum ImageFormat {
+    #[default]
+    Svg,
+    Png,
+}
 
-                        html += format!("<typst-render-insert-image-{file}>").as_str();
-                    }
-                    new_events.push(Event::Start(Tag::HtmlBlock));
-                    new_events.push(Event::Html(
-                        format!(r#"<div style="margin-bottom: 0.5em">{}</div>"#, html).into(),
-                    ));
-                    new_events.push(Event::End(TagEnd::HtmlBlock));
-                    new_events.push(Event::HardBreak);
-                    current_codeblock = None
-                }
-                None => new_events.push(event),
-            },
-            Event::Code(code) if settings.highlight_inline() => {
-                new_events.push(Event::InlineHtml(highlight(code.as_ref(), true).into()))
-            }
-            Event::Text(ref s) => match current_codeblock {
-                Some((_, ref mut text)) => {
-                    text.push_str(s);
-                }
-                None => new_events.push(event),
-            },
-            ev => new_events.push(ev),
+impl ImageFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Svg => "svg",
+            ImageFormat::Png => "png",
         }
     }
+}
 
-    let runtime = tokio::runtime::Builder::new_current_thread().build()?;
-
-    runtime.block_on(async { join_all(compile_errors).await });
-
-    // Okay, all images are rendered now, so it's time to replace file names with true ones!
-
-    let new_events = new_events.into_iter().map(|e| match e {
-            Event::Html(s) if s.contains("<typst-render-insert-image-") => {
-                const PATTLENGTH: usize = "<typst-render-insert-image-".len();
-
-                let start = s.find("<typst-render-insert-image-").unwrap();
-                let end = start
-                    + PATTLENGTH
-                    + s[start + PATTLENGTH..]
-                        .find('>')
-                        .expect("Someone who inserts crazy tags forgot to close the bracket");
-                let file = PathBuf::from_str(&s[start + PATTLENGTH..end])
-                    .expect("Problem when decoding path");
+/// Format of files that are only linked from the page
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+enum DownloadFormat {
+    Svg,
+    Png,
+    Pdf,
+}
 
-                let inner = get_images(file)
-                    .map(|name| {
-                        format!(
-                            r#"<div style="text-align: center; padding: 0.5em; background: var(--quote-bg);">
-                            <img align="middle" src="typst-img/{name}" alt="Rendered image" style="background: white; max-width: 500pt; width: 100%;">
-                            </div>"#
-                        )
-                    })
-                    .collect::<String>();
+impl DownloadFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            DownloadFormat::Svg => "svg",
+            DownloadFormat::Png => "png",
+            DownloadFormat::Pdf => "pdf",
+        }
+    }
 
-                let new_s = s[..start].to_owned() + inner.as_str() + &s[end + 1..];
+    fn label(self) -> &'static str {
+        match self {
+            DownloadFormat::Svg => "SVG",
+            DownloadFormat::Png => "PNG",
+            DownloadFormat::Pdf => "PDF",
+        }
+    }
 
-                Event::Html(new_s.into())
+    /// Output path for Typst and the first file it writes, for `rendered` without the page number
+    /// and extension. A PDF has all pages in one file
+    fn files(self, rendered: &Path) -> (PathBuf, PathBuf) {
+        let base = rendered.file_name().unwrap().to_string_lossy();
+        let extension = self.extension();
+        match self {
+            DownloadFormat::Pdf => {
+                let pdf = rendered.with_file_name(format!("{base}.pdf"));
+                (pdf.clone(), pdf)
             }
-            e => e,
-        });
-
-    let mut buf = String::with_capacity(chapter.content.len());
-    cmark(new_events.into_iter(), &mut buf)
-        .map_err(|err| anyhow!("Markdown serialization failed: {}", err))?;
-
-    chapter.content = buf;
+            _ => (
+                rendered.with_file_name(format!("{base}-{{n}}.{extension}")),
+                rendered.with_file_name(format!("{base}-1.{extension}")),
+            ),
+        }
+    }
+}
 
-    Ok(())
+/// Element around highlighted inline code
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum InlineWrapper {
+    /// `<code>` with the class of inline code
+    #[default]
+    Code,
+    /// `<span>` with the same class, without the styling of code
+    Span,
+    /// Only the highlighted spans
+    None,
 }
 
-fn codeblock_lang<'a>(
-    kind: &'a CodeBlockKind,
-    settings: &PreprocessSettings,
-    chapter: &str,
-) -> Option<&'a str> {
-    let default = if settings.typst_default {
-        Some("typ")
-    } else {
-        None
-    };
-    match kind {
-        CodeBlockKind::Fenced(kind) => {
-            if !kind.is_empty() {
-                Some(kind.as_ref())
-            } else {
-                if settings.warn_not_specified {
-                    eprintln!("Codeblock language not specified in {}", chapter)
-                }
-                default
-            }
+impl InlineWrapper {
+    fn wrap(self, class: &str, html: &str) -> String {
+        match self {
+            InlineWrapper::Code => format!(r#"<code class="{}">{html}</code>"#, escape_html(class)),
+            InlineWrapper::Span => format!(r#"<span class="{}">{html}</span>"#, escape_html(class)),
+            InlineWrapper::None => html.to_owned(),
         }
-        CodeBlockKind::Indented => default,
     }
 }
 
-fn is_typst_codeblock(s: &str) -> bool {
-    s.contains("typ") || s.contains("typst")
+/// Space after highlighted blocks
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum BlockSpacing {
+    /// A margin under the block and a line break after it
+    #[default]
+    Break,
+    /// Only the margin
+    Margin,
+    /// Neither, the theme's own margins are left
+    None,
 }
 
-fn highlight(src: &str, inline: bool) -> String {
-    let src = src.strip_suffix('\n').unwrap_or(src);
-
-    let syntax = SYNTAX.syntaxes().last().unwrap();
-
-    let mut html = if inline {
-        let mut h = HighlightLines::new(syntax, &THEME);
-        let regs = h.highlight_line(src, &SYNTAX).unwrap(); // everything should be fine
-        let html = styled_line_to_highlighted_html(&regs[..], IncludeBackground::No).unwrap();
-        format!(r#"<code class="hljs">{}</code>"#, html)
-    } else {
-        let mut html = r#"<pre style="margin: 0"><code class="language-typ hljs">"#.into();
-
-        let mut highlighter = HighlightLines::new(syntax, &THEME);
-
-        for line in LinesWithEndings::from(src) {
-            let regions = highlighter.highlight_line(line, &SYNTAX).unwrap();
-            append_highlighted_html_for_styled_line(&regions[..], IncludeBackground::No, &mut html)
-                .unwrap();
+impl BlockSpacing {
+    /// Style of the element around the block
+    fn margin(self) -> &'static str {
+        match self {
+            BlockSpacing::None => "",
+            _ => "margin-bottom: 0.5em",
         }
+    }
 
-        html.push_str("</code></pre>");
+    fn hard_break(self) -> bool {
+        self == BlockSpacing::Break
+    }
+}
 
-        html
-    };
+/// Horizontal alignment of rendered images, `align`
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum ImageAlign {
+    Left,
+    #[default]
+    Center,
+    Right,
+}
 
-    html = html.replace("#1bdf3363", "var(--fg)");
+impl ImageAlign {
+    /// Value of `align=`, if it is one of the alignments
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "left" => Some(ImageAlign::Left),
+            "center" => Some(ImageAlign::Center),
+            "right" => Some(ImageAlign::Right),
+            _ => None,
+        }
+    }
 
-    html
+    /// CSS `text-align` of the box around images
+    fn text_align(self) -> &'static str {
+        match self {
+            ImageAlign::Left => "left",
+            ImageAlign::Center => "center",
+            ImageAlign::Right => "right",
+        }
+    }
 }
 
-fn sha256_hash(input: &str) -> String {
-    let hash = Sha256::digest(input.as_bytes());
-    format!("{:x}", hash)
+/// What to do with inline code that is a part of a link text
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum InlineInLinks {
+    /// Highlight it, escaping brackets so that the link stays intact
+    #[default]
+    Highlight,
+    /// Leave it as plain inline code
+    Plain,
 }
 
-fn get_images(src: PathBuf) -> impl Iterator<Item = String> {
-    let mut n = 1;
-    let fbase = src.file_name().unwrap().to_str().unwrap().to_owned();
+impl PreprocessConfig {
+    /// Whether blocks of the chapter are rendered according to `render_include` and `render_exclude`
+    fn renders_chapter(&self, chapter: &Chapter) -> bool {
+        let path = chapter
+            .source_path
+            .as_ref()
+            .or(chapter.path.as_ref())
+            .map(|path| slash_path(path))
+            .unwrap_or_default();
+        let matches = |pattern: &String| glob_match(pattern, &path);
 
-    iter::from_fn(move || {
-        let path = src.clone();
-        let path = path.with_file_name(fbase.clone() + format!("-{n}.svg").as_str());
+        (self.render_include.is_empty() || self.render_include.iter().any(matches))
+            && !self.render_exclude.iter().any(matches)
+    }
 
-        if path.exists() {
-            n += 1;
-            Some(path.file_name().unwrap().to_string_lossy().into_owned())
-        } else {
-            None
-        }
-    })
-    .fuse()
-}
-
-fn render_block(
-    src: String,
-    mut dir: PathBuf,
-    mut build_dir: PathBuf,
-    name: String,
-    preamble: bool,
-) -> (PathBuf, Option<impl Future<Output = ()>>) {
-    let filename = sha256_hash(&src);
-    let mut output = dir.clone();
-    output.push("typst-img");
-
-    let mut check = output.clone();
-    let mut cut_output = output.clone();
-    cut_output.push(filename.clone());
-
-    output.push(filename.clone() + "-{n}.svg");
-    check.push(filename.clone() + "-1.svg");
-
-    let mut command = None;
-
-    if !check.exists() {
-        fs::create_dir_all(output.parent().unwrap()).expect("Can't create a dir");
-        dir.push("typst-src");
-        fs::create_dir_all(&dir).expect("Can't create a dir");
-        dir.push(filename.clone() + ".typ");
-
-        let mut file = File::create(&dir).expect("Can't create file");
-        if preamble {
-            writeln!(file, "{}", PREAMBLE).expect("Error writing to file")
+    /// Cached files are compiled again, golden tests always check fresh images
+    #[inline(always)]
+    fn forced(&self) -> bool {
+        self.force_render || self.golden.is_some()
+    }
+
+    /// Formats rendered besides `format`, `pdf = true` is the same as listing `pdf`
+    fn extra_formats(&self) -> Vec<DownloadFormat> {
+        let mut formats = self.formats.clone();
+        if self.pdf {
+            formats.push(DownloadFormat::Pdf);
+        }
+        let mut seen = BTreeSet::new();
+        formats
+            .retain(|format| format.extension() != self.format.extension() && seen.insert(*format));
+        formats
+    }
+
+    #[inline(always)]
+    fn highlight_inline(&self) -> bool {
+        !self.disable_inline
+    }
+
+    /// Whether the theme foreground is replaced with [`Self::fg_var`],
+    /// only done for the default theme
+    #[inline(always)]
+    fn foreground_hack(&self) -> bool {
+        self.theme.is_none()
+            && self.theme_file.is_none()
+            && !self.dual_themes()
+            && !self.disable_foreground_hack
+            // Text on the theme's background needs the theme's foreground
+            && !self.include_background
+    }
+
+    #[inline(always)]
+    fn fg_var(&self) -> &str {
+        self.fg_var.as_deref().unwrap_or(DEFAULT_FG_VAR)
+    }
+
+    #[inline(always)]
+    fn wrapper_bg(&self) -> &str {
+        self.wrapper_bg.as_deref().unwrap_or(DEFAULT_WRAPPER_BG)
+    }
+
+    /// `PREAMBLE` with the page settings, the raw `preamble` takes precedence over them
+    fn page_preamble(&self) -> String {
+        if self.page_width.is_none() && self.page_height.is_none() && self.margin.is_none() {
+            return PREAMBLE.to_owned();
+        }
+
+        format!(
+            "#set page(height: {}, width: {}, margin: {})\n",
+            self.page_height.as_deref().unwrap_or("auto"),
+            self.page_width.as_deref().unwrap_or("400pt"),
+            self.margin.as_deref().unwrap_or("0.5cm")
+        )
+    }
+
+    /// Source of a block as it is highlighted and rendered, so that line endings
+    /// of the platform the book was written on don't change images or their names
+    fn block_source(&self, text: String) -> String {
+        let mut text = if text.contains('\r') {
+            text.replace("\r\n", "\n")
+        } else {
+            text
+        };
+
+        if self.trim_trailing.unwrap_or(true) {
+            // The last line with something on it keeps its line break
+            let content = text.trim_end().len();
+            let end = text[content..]
+                .find('\n')
+                .map_or(text.len(), |newline| content + newline + 1);
+            text.truncate(if content == 0 { 0 } else { end });
+        }
+
+        text
+    }
+
+    #[inline(always)]
+    fn lazy_loading(&self) -> bool {
+        self.lazy_loading.unwrap_or(true)
+    }
+
+    #[inline(always)]
+    fn preamble_enabled(&self) -> bool {
+        self.preamble_enabled.unwrap_or(true)
+    }
+
+    #[inline(always)]
+    fn dual_themes(&self) -> bool {
+        self.light_theme.is_some() && self.dark_theme.is_some()
+    }
+
+    /// `sys.inputs` for a block, `input.key=value` in the info string overrides the settings
+    fn block_inputs(&self, attrs: &BlockAttrs) -> BTreeMap<String, String> {
+        let mut inputs = self.inputs.clone();
+        inputs.extend(
+            attrs
+                .inputs()
+                .map(|(key, value)| (key.to_owned(), value.to_owned())),
+        );
+        inputs
+    }
+
+    /// `#set text(size: ...)` of a block, `textsize=` in the info string overrides the settings
+    fn text_size_rule(&self, attrs: &BlockAttrs, chapter: &str) -> Option<String> {
+        let size = attrs
+            .parsed("textsize", chapter, "a Typst length", |size| {
+                is_text_size(size).then_some(size)
+            })
+            .or(self.render_text_size.as_deref())?;
+
+        Some(format!("#set text(size: {size})\n"))
+    }
+
+    /// Preamble for a rendered block with the given options
+    fn block_preamble(&self, attrs: &BlockAttrs, text_size: Option<&str>) -> String {
+        let mut preamble = match &self.preamble {
+            Some(preamble) => preamble.clone(),
+            None => self.page_preamble(),
+        };
+        if let Some(text_size) = text_size {
+            preamble += text_size;
+        }
+
+        let justify = if attrs.flag("nojustify") {
+            Some(false)
+        } else if attrs.flag("justify") {
+            Some(true)
+        } else {
+            self.render_par_justify
+        };
+
+        let mut par = vec![];
+        if let Some(justify) = justify {
+            par.push(format!("justify: {justify}"));
+        }
+        if let Some(leading) = &self.render_par_leading {
+            par.push(format!("leading: {leading}"));
+        }
+        if let Some(spacing) = &self.render_par_spacing {
+            par.push(format!("spacing: {spacing}"));
+        }
+        if !par.is_empty() {
+            preamble += &format!("#set par({})\n", par.join(", "));
+        }
+
+        preamble
+    }
+
+    #[inline(always)]
+    fn ppi(&self) -> f64 {
+        self.ppi.unwrap_or(144.0)
+    }
+
+    #[inline(always)]
+    fn srcset(&self) -> bool {
+        self.srcset && self.format == ImageFormat::Png
+    }
+
+    /// Arguments of `typst c` set in the config, they are part of the cache key
+    fn typst_args(&self) -> Vec<String> {
+        let features =
+            (!self.features.is_empty()).then(|| ["--features".to_owned(), self.features.join(",")]);
+        features
+            .into_iter()
+            .flatten()
+            .chain(self.extra_args.iter().cloned())
+            .collect()
+    }
+
+    #[inline(always)]
+    fn typst(&self) -> &Path {
+        self.typst_path.as_deref().unwrap_or(Path::new("typst"))
+    }
+
+    #[inline(always)]
+    fn thumbnail_size(&self) -> f64 {
+        self.thumbnail_size.unwrap_or(150.0)
+    }
+
+    #[inline(always)]
+    fn max_width(&self) -> &str {
+        self.max_width.as_deref().unwrap_or("500pt")
+    }
+
+    #[inline(always)]
+    fn math_vertical_align(&self) -> &str {
+        self.math_vertical_align.as_deref().unwrap_or("middle")
+    }
+
+    /// Slugified `filename_scheme` for a block, `number` is 1-based among the rendered blocks of the chapter
+    fn file_slug(&self, chapter: &str, caption: Option<&str>, number: usize) -> Option<String> {
+        let name = self
+            .filename_scheme
+            .as_deref()?
+            .replace("{chapter}", chapter)
+            .replace("{caption}", caption.unwrap_or_default())
+            .replace("{n}", &number.to_string());
+        let slug = slugify(&name);
+        (!slug.is_empty()).then_some(slug)
+    }
+
+    /// Style of the backing behind rendered images, followed by a space
+    #[inline(always)]
+    fn image_background(&self) -> &'static str {
+        if self.transparent_background {
+            ""
+        } else {
+            "background: white; "
+        }
+    }
+}
+
+impl PreprocessSettings {
+    /// Theme used when no theme is set explicitly
+    #[inline(always)]
+    fn default_theme(&self) -> &str {
+        self.book_theme.as_deref().unwrap_or(DEFAULT_THEME)
+    }
+
+    #[inline(always)]
+    fn syntax_set(&self) -> &SyntaxSet {
+        self.syntax_set.as_ref().unwrap_or(&SYNTAX)
+    }
+}
+
+impl Preprocessor for TypstHighlight {
+    fn name(&self) -> &str {
+        "typst-highlight"
+    }
+
+    fn run(&self, ctx: &PreprocessorContext, mut book: Book) -> Result<Book, Error> {
+        let mut config = ctx
+            .config
+            .get::<PreprocessConfig>("preprocessor.typst-highlight")?
+            .unwrap_or_default();
+
+        let unknown = config
+            .unknown
+            .keys()
+            .filter(|key| !MDBOOK_KEYS.contains(&key.as_str()))
+            .map(|key| format!("`{key}`"))
+            .collect::<Vec<_>>();
+        if !unknown.is_empty() {
+            return Err(anyhow!(
+                "Unknown config in [preprocessor.typst-highlight]: {}, check them for typos",
+                unknown.join(", ")
+            ));
+        }
+
+        config.limits.validate()?;
+
+        if let Some(width) = &config.max_width
+            && !is_css_length(width)
+        {
+            log::warn!("Ignoring max_width = \"{width}\", it isn't a CSS length");
+            config.max_width = None;
+        }
+
+        if let Some(scale) = config.scale
+            && !is_scale(scale)
+        {
+            log::warn!("Ignoring scale = {scale}, it isn't a positive number");
+            config.scale = None;
+        }
+
+        if let Some(scheme) = &config.filename_scheme {
+            let rest = FILENAME_PLACEHOLDERS
+                .iter()
+                .fold(scheme.clone(), |rest, placeholder| {
+                    rest.replace(placeholder, "")
+                });
+            if rest.contains(['{', '}']) {
+                log::warn!(
+                    "Ignoring filename_scheme = \"{scheme}\", its only placeholders can be {}",
+                    FILENAME_PLACEHOLDERS.join(", ")
+                );
+                config.filename_scheme = None;
+            }
+        }
+
+        let formats = [config.format.extension()]
+            .into_iter()
+            .chain(
+                config
+                    .extra_formats()
+                    .into_iter()
+                    .map(DownloadFormat::extension),
+            )
+            .collect::<Vec<_>>();
+        if config.picture && !(formats.contains(&"svg") && formats.contains(&"png")) {
+            log::warn!("Ignoring picture = true, blocks have to be rendered to both SVG and PNG");
+            config.picture = false;
+        }
+        // Thumbnails are the SVGs drawn smaller, PNGs would need to be resampled
+        if config.thumbnail && config.format == ImageFormat::Png {
+            log::warn!("Ignoring thumbnail = true, thumbnails can only be made of SVG images");
+            config.thumbnail = false;
+        }
+
+        for (name, length) in [
+            ("page_width", &mut config.page_width),
+            ("page_height", &mut config.page_height),
+            ("margin", &mut config.margin),
+        ] {
+            if let Some(value) = length
+                && !is_typst_length(value)
+            {
+                log::warn!("Ignoring {name} = \"{value}\", it isn't a Typst length");
+                *length = None;
+            }
+        }
+        if let Some(size) = &config.render_text_size
+            && !is_text_size(size)
+        {
+            log::warn!("Ignoring render_text_size = \"{size}\", it isn't a Typst length");
+            config.render_text_size = None;
+        }
+
+        match ctx.renderer.as_str() {
+            "html" => {}
+            // mdbook-epub copies only images it finds in markdown, and raw `<img>` isn't valid XHTML
+            "epub" => {
+                config.markdown_figures = true;
+                config.thumbnail = false;
+            }
+            renderer
+                if renderer == PRINT_RENDERER
+                    || config.print_renderers.iter().any(|r| r == renderer) =>
+            {
+                // Print renderers read images from markdown, there is nothing to click on paper
+                config.markdown_figures = true;
+                config.format = ImageFormat::Png;
+                config.pdf = true;
+                config.thumbnail = false;
+                config.inline_svg = false;
+                config.copy_button = false;
+            }
+            // There is nothing to show images with, so code is only highlighted
+            _ => config.render = false,
+        }
+
+        if let Some(golden) = config.golden.as_mut() {
+            *golden = ctx.root.join(&golden);
+        }
+
+        // Bare names are looked up in PATH, other relative paths are relative to the book
+        if let Some(typst) = config.typst_path.as_mut()
+            && typst.is_relative()
+            && typst.components().count() > 1
+        {
+            *typst = ctx.root.join(&typst);
+        }
+
+        for path in [
+            &mut config.package_path,
+            &mut config.package_cache_path,
+            &mut config.typst_root,
+            &mut config.out_dir,
+            &mut config.highlight_css,
+            &mut config.theme_file,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            *path = ctx.root.join(&path);
+        }
+
+        // Without compiling, there is nothing to compare
+        if config.dry_run {
+            config.golden = None;
+        }
+        // Images are compared line by line, which means nothing for raster images
+        if config.golden.is_some() && config.format == ImageFormat::Png {
+            return Err(anyhow!(
+                "golden only compares SVG images, it can't be used with format = \"png\""
+            ));
+        }
+
+        if env_flag(std::env::var_os("TYPST_HIGHLIGHT_FORCE")) {
+            config.force_render = true;
+        }
+        if env_flag(std::env::var_os("TYPST_HIGHLIGHT_OFFLINE")) {
+            config.skip_render_on_offline = true;
+        }
+        if config.render && config.force_render {
+            log::info!("Ignoring the cache, every block is compiled again");
+        }
+
+        // Books that don't choose a theme keep the highlighting they always had
+        let book_theme = ctx
+            .config
+            .get::<String>("output.html.default-theme")?
+            .and_then(|book_theme| {
+                config.theme_map.get(&book_theme).cloned().or_else(|| {
+                    BOOK_THEMES
+                        .iter()
+                        .find(|(name, _)| *name == book_theme)
+                        .map(|(_, theme)| (*theme).to_owned())
+                })
+            });
+
+        let font_dirs = config
+            .font_paths
+            .iter()
+            .map(|path| ctx.root.join(path))
+            .collect();
+
+        let mut extra_languages = BTreeSet::new();
+        let syntax_set = if !config.extra_syntaxes.is_empty() {
+            let mut syntaxes = SYNTAX.clone().into_builder();
+            for path in &config.extra_syntaxes {
+                let path = ctx.root.join(path);
+                let syntax = fs::read_to_string(&path)
+                    .map_err(Error::from)
+                    .and_then(|syntax| Ok(SyntaxDefinition::load_from_str(&syntax, true, None)?))
+                    .map_err(|e| anyhow!("Can't load syntax {}: {e}", path.display()))?;
+                extra_languages.extend(syntax.file_extensions.iter().cloned());
+                syntaxes.add(syntax);
+            }
+            Some(syntaxes.build())
+        } else if config.minimal_syntaxes {
+            // Raw blocks in other languages inside Typst code are left plain then
+            Some(from_binary(include_bytes!(concat!(
+                env!("OUT_DIR"),
+                "/typst.packdump"
+            ))))
+        } else {
+            None
+        };
+
+        let mut build_dir = ctx.root.clone();
+        build_dir.push(&ctx.config.book.src);
+
+        // Rendered files are written next to chapters, unless we are told or aren't allowed to
+        let mut out_dir_url = None;
+        let mut embed_assets = false;
+        let cache_dir = if let Some(out_dir) = config.out_dir.clone() {
+            // mdBook copies only the sources to the output (and empties it first, so nothing can
+            // be copied there), a directory outside of them is linked to from the HTML output
+            let relative = match out_dir.strip_prefix(&build_dir) {
+                Ok(relative) => Some(relative.to_path_buf()),
+                Err(_) => relative_path(&html_dir(ctx), &out_dir),
+            };
+            match relative {
+                Some(relative) => out_dir_url = Some(slash_path(&relative)),
+                None => embed_assets = true,
+            }
+            out_dir
+        } else if config.render && !is_writable(&build_dir) {
+            // Every book has its own, so that pruning one doesn't delete images of the others
+            let root = fs::canonicalize(&ctx.root).unwrap_or_else(|_| ctx.root.clone());
+            let cache_dir = std::env::temp_dir()
+                .join("mdbook-typst-highlight")
+                .join(&sha256_hash(&root.to_string_lossy())[..SHORT_HASH_LEN]);
+            log::info!(
+                "{} is read-only, rendered images will be kept in {} and embedded into pages",
+                build_dir.display(),
+                cache_dir.display()
+            );
+            embed_assets = true;
+            cache_dir
+        } else {
+            build_dir.clone()
+        };
+
+        let mut settings = PreprocessSettings {
+            config,
+            book_theme,
+            style_classes: StyleClasses::default(),
+            src_dir: build_dir,
+            out_dir_url,
+            cache_dir,
+            embed_assets,
+            font_dirs,
+            font_faces: vec![],
+            syntax_set,
+            extra_languages,
+            typst_version: String::new(),
+        };
+
+        // Without Typst, a book whose images are all cached can still be built
+        let mut typst_ran = false;
+        if settings.render {
+            settings.typst_version = match typst_version(settings.typst()) {
+                Ok(version) => {
+                    typst_ran = true;
+                    version
+                }
+                Err(e) => match recorded_typst_version(&settings.cache_dir) {
+                    Some(version) => {
+                        log::warn!(
+                            "Typst can't be run as `{}`: {e}\n\
+                             Images rendered by {} are taken from the cache, blocks that aren't there fail",
+                            settings.typst().display(),
+                            version.trim()
+                        );
+                        version
+                    }
+                    None => {
+                        return Err(anyhow!(
+                            "Rendering is enabled, but Typst can't be run as `{}`: {e}\n\
+                             Install Typst (https://github.com/typst/typst#installation) and make sure \
+                             it is in PATH or set `typst_path`, or set `render = false` to only highlight code",
+                            settings.typst().display()
+                        ));
+                    }
+                },
+            };
+
+            if let (Some(installed), Some(syntax)) = (
+                parse_version(&settings.typst_version),
+                parse_version(SYNTAX_TYPST_VERSION),
+            ) && installed > syntax
+            {
+                log::warn!(
+                    "Typst {} is newer than the syntax used for highlighting, which covers Typst {}, \
+                     so newer syntax may be highlighted incorrectly",
+                    format_args!("{}.{}.{}", installed.0, installed.1, installed.2),
+                    SYNTAX_TYPST_VERSION
+                );
+            }
+        }
+
+        if settings.embed_fonts && settings.render && !settings.dry_run {
+            if settings.inline_svg && settings.format == ImageFormat::Svg {
+                let dirs = iter::once(settings.src_dir.join("fonts"))
+                    .chain(settings.font_dirs.iter().cloned())
+                    .collect::<Vec<_>>();
+                settings.font_faces = convert_fonts(&dirs, &settings.cache_dir.join("typst-fonts"));
+            } else {
+                log::warn!(
+                    "Ignoring embed_fonts, only inline SVGs use the fonts of the page, see inline_svg"
+                );
+            }
+        }
+
+        let run = RunState {
+            settings: &settings,
+            themes: Arc::new(Themes::load(&settings)?),
+            progress: Rc::new(Progress::new(
+                settings.verbose,
+                settings.forced(),
+                settings.dry_run,
+            )),
+            manifests: Rc::new(Manifests::default()),
         };
-        write!(file, "{}", src).expect("Error writing to file");
 
-        let mut res = Command::new("typst");
-        let mut res = res
-            .arg("c")
-            .arg(&dir)
-            .arg("--root")
-            .arg(dir.parent().unwrap().parent().unwrap())
-            .arg(&output);
+        let mut errors = vec![];
+
+        // Cached files used by the book, see `render_block`
+        let mut used = BTreeSet::new();
+
+        // Blocks of all chapters are rendered at once, so that the limit of parallel renders is global
+        let mut prepared = vec![];
+        let mut renders = vec![];
+        let mut jobs = HighlightJobs::default();
 
-        build_dir.push("fonts");
+        book.for_each_chapter_mut(|chapter| {
+            match prepare_chapter(chapter, &run, &mut jobs, &mut used) {
+                Ok((chapter, chapter_renders)) => {
+                    let index = prepared.len();
+                    renders.extend(chapter_renders.into_iter().map(move |render| {
+                        render.map(move |error| error.map(|error| (index, error)))
+                    }));
+                    prepared.push(Some(chapter));
+                }
+                Err(e) => {
+                    errors.push(e);
+                    prepared.push(None);
+                }
+            }
+        });
 
-        if build_dir.exists() {
-            res = res.arg("--font-path").arg(build_dir)
+        let mut render_errors = vec![vec![]; prepared.len()];
+        // Typst processes are awaited on this thread, async-io drives them and timeouts,
+        // while the code of the whole book is highlighted by the other cores
+        let (finished, highlighted) = thread::scope(|scope| {
+            let highlighting = scope.spawn(|| jobs.run(&settings));
+            let finished = async_io::block_on(async {
+                stream::iter(renders)
+                    .buffer_unordered(settings.limits.max_parallel())
+                    .collect::<Vec<_>>()
+                    .await
+            });
+            let highlighted = highlighting
+                .join()
+                .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+            (finished, highlighted)
+        });
+        for (index, error) in finished.into_iter().flatten() {
+            render_errors[index].push(error);
         }
 
-        let res = res.output();
+        if settings.render {
+            run.progress.summary();
+        }
 
-        command = Some(async move {
-            let output = res.await.expect("Failed").stderr;
+        if settings.emit_index && !settings.dry_run {
+            let index = prepared
+                .iter_mut()
+                .flatten()
+                .flat_map(|chapter| &mut chapter.images)
+                .filter_map(|block| {
+                    let mut entry = block.index.take()?;
+                    let dir = block.file.parent().unwrap();
+                    let dir = dir.strip_prefix(&settings.cache_dir).unwrap_or(dir);
+                    entry.files = get_images(block.file.clone(), settings.format.extension())
+                        .map(|name| slash_path(&dir.join(name)))
+                        .collect();
+                    Some(entry)
+                })
+                .collect::<Vec<_>>();
+            let path = ctx.root.join(
+                settings
+                    .index_path
+                    .as_deref()
+                    .unwrap_or(Path::new("typst-index.json")),
+            );
+            if let Err(e) = serde_json::to_string_pretty(&index)
+                .map_err(Error::from)
+                .and_then(|json| Ok(fs::write(&path, json + "\n")?))
+            {
+                errors.push(anyhow!("Can't write {}: {e}", path.display()));
+            }
+        }
 
-            if !output.is_empty() {
-                let stderr = std::io::stderr();
-                let mut handle = stderr.lock();
-                writeln!(handle, "Error at chapter \"{}\"\n", name).expect("Can't write to stderr");
-                handle.write_all(&output).expect("Can't write to stderr");
+        // Chapters are visited in the same order as before
+        let mut prepared = prepared.into_iter().zip(render_errors);
+        book.for_each_chapter_mut(|chapter| {
+            if let Some((Some(prepared), render_errors)) = prepared.next()
+                && let Err(e) = finish_chapter(chapter, prepared, render_errors, &run, &highlighted)
+            {
+                errors.push(e);
             }
         });
+
+        if let Some(path) = settings
+            .highlight_css
+            .as_ref()
+            .filter(|_| settings.highlight_classes)
+            && let Err(e) = fs::write(path, settings.style_classes.css())
+        {
+            errors.push(anyhow!("Can't write {}: {e}", path.display()));
+        }
+
+        // After a failure not every block may have been seen
+        let prune =
+            settings.prune_cache && settings.render && !settings.dry_run && errors.is_empty();
+        if prune {
+            prune_cache(&settings.cache_dir, &used, &mut errors);
+        }
+        if !settings.dry_run {
+            run.manifests.save(prune.then_some(&used));
+            if typst_ran {
+                record_typst_version(&settings.cache_dir, &settings.typst_version);
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(book)
+        } else {
+            Err(anyhow!(
+                "Errors occurred during preprocessing:\n{:#?}",
+                errors
+            ))
+        }
+    }
+
+    fn supports_renderer(&self, renderer: &str) -> Result<bool> {
+        Ok(matches!(
+            renderer,
+            "html" | "epub" | "markdown" | PRINT_RENDERER
+        ))
+    }
+}
+
+/// A chapter with highlighted code, waiting for its blocks to be rendered
+struct PreparedChapter {
+    events: Vec<Event<'static>>,
+    /// Rendered files to check against golden images, with their names there
+    rendered: Vec<(PathBuf, String)>,
+    /// Blocks whose images are inserted in place of `<typst-render-insert-...-{index}>`
+    images: Vec<BlockImages>,
+}
+
+/// Images of a rendered block, see `render_block`
+struct BlockImages {
+    /// Rendered file without the page number and extension
+    file: PathBuf,
+    /// CSS `max-width` of the images
+    width: String,
+    /// Formulas are small, so they aren't stretched like other images
+    math: bool,
+    /// Escaped description of the images
+    alt: Option<String>,
+    /// Style of the backing behind the images, `bg=` overrides `image_background`
+    background: String,
+    /// Factor of the typeset size, `scale=` overrides `scale`
+    scale: Option<f64>,
+    /// Where the images are in their box, `align=` overrides `align`
+    align: ImageAlign,
+    /// Tooltip of the images, `title=`, not escaped
+    title: Option<String>,
+    /// 1-based numbers of the pages to show, `pages=`, all of them if none
+    pages: Option<RangeInclusive<usize>>,
+    /// Entry of `typst-index.json`, if it is written
+    index: Option<IndexEntry>,
+}
+
+/// A rendered block in `typst-index.json`
+#[derive(Serialize)]
+struct IndexEntry {
+    /// Name of the chapter
+    chapter: String,
+    /// Path of the chapter in the book sources, none for draft chapters
+    path: Option<String>,
+    /// Cache key of the block, the common part of its file names
+    hash: String,
+    /// Images of the block in page order, relative to the cache directory, see `out_dir`
+    files: Vec<String>,
+    /// Text of `caption=`, without the figure number
+    caption: Option<String>,
+    /// Description of the block, `alt=` or a `// alt:` comment
+    alt: Option<String>,
+}
+
+impl BlockImages {
+    /// `alt` of the `page`, which is 0-based
+    fn alt(&self, page: usize, pages: usize) -> String {
+        let alt = self.alt.as_deref().unwrap_or("Rendered image");
+        if pages > 1 {
+            format!("{alt} (page {})", page + 1)
+        } else {
+            alt.to_owned()
+        }
     }
 
-    (cut_output, command)
+    /// Pages of `names` picked by `pages=` with their 0-based numbers, missing ones are skipped with a warning
+    fn select_pages(&self, names: Vec<String>, chapter: &str) -> Vec<(usize, String)> {
+        let Some(range) = &self.pages else {
+            return names.into_iter().enumerate().collect();
+        };
+
+        if *range.end() > names.len() {
+            log::warn!(
+                "A block in chapter \"{chapter}\" has only {} pages, those after it in pages= aren't shown",
+                names.len()
+            );
+        }
+
+        names
+            .into_iter()
+            .enumerate()
+            .filter(|(page, _)| range.contains(&(page + 1)))
+            .collect()
+    }
+}
+
+/// What the chapters of a run share, see `prepare_chapter` and `finish_chapter`
+struct RunState<'a> {
+    settings: &'a PreprocessSettings,
+    themes: Arc<Themes>,
+    progress: Rc<Progress>,
+    manifests: Rc<Manifests>,
+}
+
+/// Highlights the chapter, the returned futures render its blocks and yield errors, if any
+fn prepare_chapter(
+    chapter: &mut Chapter,
+    run: &RunState,
+    jobs: &mut HighlightJobs,
+    used: &mut BTreeSet<PathBuf>,
+) -> Result<(
+    PreparedChapter,
+    Vec<LocalBoxFuture<'static, Option<BlockError>>>,
+)> {
+    let settings = run.settings;
+    let themes = &run.themes;
+    let build_dir = &settings.src_dir;
+    let events = new_cmark_parser(&chapter.content, &Default::default());
+    let mut new_events = Vec::new();
+
+    // (lang, mode, text) of the current codeblock, blocks in extra syntaxes have no mode
+    let mut current_codeblock: Option<(String, Option<TypstMode>, String)> = None;
+
+    let mut chapter_path = settings.cache_dir.clone();
+    if let Some(p) = chapter.path.as_ref().and_then(|p| p.parent())
+        && !settings.shared_cache
+    {
+        chapter_path.push(p)
+    };
+
+    let mut source_dir = build_dir.to_path_buf();
+    if let Some(p) = chapter.path.as_ref().and_then(|p| p.parent()) {
+        source_dir.push(p)
+    };
+
+    let chapter_preamble = chapter_preamble(chapter, build_dir);
+    let renders_chapter = settings.renders_chapter(chapter);
+    // mdBook skips chapters without a path as drafts, but if one gets here,
+    // there is no directory under `src` for its images
+    let mut pathless_warned = false;
+
+    let mut compile_errors = vec![];
+
+    let mut rendered = vec![];
+    let mut images = vec![];
+
+    // How deep we are inside links, inline code there needs special care
+    let mut link_depth = 0usize;
+
+    // Number of the last captioned figure in the chapter
+    let mut figure_number = 0usize;
+
+    // Themes of `theme=` blocks, `None` for unknown ones
+    let mut block_themes = BTreeMap::new();
+
+    // Line of the chapter where the code of the current block starts, 1-based
+    let mut codeblock_line = 0;
+
+    for (event, range) in events.into_offset_iter() {
+        match event {
+            Event::Start(Tag::Link { .. }) => {
+                link_depth += 1;
+                new_events.push(event)
+            }
+            Event::End(TagEnd::Link) => {
+                link_depth = link_depth.saturating_sub(1);
+                new_events.push(event)
+            }
+            Event::Start(Tag::CodeBlock(ref kind)) => {
+                // Fenced code starts on the line after the fence
+                let fence = matches!(kind, CodeBlockKind::Fenced(_)) as usize;
+                codeblock_line = chapter.content[..range.start].matches('\n').count() + 1 + fence;
+                match codeblock_lang(kind, settings, chapter.name.as_str()) {
+                    Some(lang) if let Some(mode) = typst_mode(lang) => {
+                        current_codeblock = Some((lang.to_owned(), Some(mode), String::new()))
+                    }
+                    Some(lang) if settings.extra_languages.contains(language(lang)) => {
+                        current_codeblock = Some((lang.to_owned(), None, String::new()))
+                    }
+                    _ => new_events.push(event),
+                }
+            }
+            Event::End(TagEnd::CodeBlock) => match current_codeblock
+                .take()
+                .map(|(lang, mode, text)| (lang, mode, settings.block_source(text)))
+            {
+                // Other languages are only highlighted
+                Some((lang, None, text)) => {
+                    let attrs = BlockAttrs::parse(&lang, &chapter.name);
+                    let themes = block_theme(
+                        &mut block_themes,
+                        &attrs,
+                        &source_dir,
+                        &chapter.name,
+                        settings,
+                    )
+                    .unwrap_or_else(|| themes.clone());
+                    // Bare blocks have no `<pre>`, their wrapper gets the classes
+                    let classes = attrs.classes(&chapter.name);
+                    let (pre_class, wrapper_class) = if settings.bare_output {
+                        (None, class_attribute(classes.as_deref()))
+                    } else {
+                        (classes, String::new())
+                    };
+                    let html = jobs.push(HighlightJob {
+                        emphasized: attrs.emphasized_lines(text.lines().count()),
+                        language: attrs.language.to_owned(),
+                        pre_class,
+                        ..HighlightJob::new(text, &themes, &chapter.name)
+                    });
+                    new_events.push(Event::Start(Tag::HtmlBlock));
+                    let label = attrs.file_label();
+                    let margin = settings.block_spacing.margin();
+                    new_events.push(Event::Html(
+                        format!(r#"<div{wrapper_class} style="{margin}">{label}{html}</div>"#)
+                            .into(),
+                    ));
+                    new_events.push(Event::End(TagEnd::HtmlBlock));
+                    if settings.block_spacing.hard_break() {
+                        new_events.push(Event::HardBreak);
+                    }
+                    current_codeblock = None
+                }
+                Some((lang, Some(mode), text)) => {
+                    let attrs = BlockAttrs::parse(&lang, &chapter.name);
+                    let themes = block_theme(
+                        &mut block_themes,
+                        &attrs,
+                        &source_dir,
+                        &chapter.name,
+                        settings,
+                    )
+                    .unwrap_or_else(|| themes.clone());
+                    let mut figure = None;
+                    // ` data-typst-src="..."` of the wrapper, if the block is rendered
+                    let mut embedded = None;
+                    // Placeholders of the rendered images, they go after or next to the code
+                    let mut image_html = String::new();
+
+                    let fits = settings.limits.snippet_fits(&text);
+                    if !fits {
+                        log::warn!(
+                            "Snippet in chapter \"{}\" exceeds limits.max_snippet_bytes and won't be rendered",
+                            chapter.name
+                        );
+                    }
+
+                    let mut render =
+                        settings.render && renders_chapter && !attrs.flag("norender") && fits;
+
+                    if render && chapter.path.is_none() {
+                        if !pathless_warned {
+                            log::warn!(
+                                "Chapter \"{}\" has no path, its blocks are highlighted but not rendered",
+                                chapter.name
+                            );
+                            pathless_warned = true;
+                        }
+                        render = false;
+                    }
+
+                    if attrs.flag("hidesrc") && attrs.flag("norender") {
+                        compile_errors.push(
+                            future::ready(Some((
+                                None,
+                                format!(
+                                    "Error at chapter \"{}\"\n\nA block can't be both hidesrc and norender, its code is shown\n",
+                                    chapter.name
+                                ),
+                            )))
+                            .boxed_local(),
+                        );
+                    }
+
+                    let text_size = settings.text_size_rule(&attrs, &chapter.name);
+                    let mut preamble = (settings.preamble_enabled() && !attrs.flag("nopreamble"))
+                        .then(|| {
+                            settings.block_preamble(&attrs, text_size.as_deref())
+                                + &chapter_preamble
+                        });
+
+                    // The contents of the file become a part of the preamble, so they are hashed too
+                    let extra_preamble = attrs.get("preamble").filter(|_| render);
+                    let mut preamble_ok = true;
+                    if let (Some(preamble), Some(extra)) = (preamble.as_mut(), extra_preamble) {
+                        match fs::read_to_string(source_dir.join(extra)) {
+                            Ok(extra) => {
+                                *preamble += &extra;
+                                preamble.push('\n');
+                            }
+                            Err(e) => {
+                                preamble_ok = false;
+                                compile_errors.push(
+                                    future::ready(Some((
+                                        None,
+                                        format!(
+                                            "Error at chapter \"{}\"\n\nCan't read preamble {}: {}\n",
+                                            chapter.name, extra, e
+                                        ),
+                                    )))
+                                    .boxed_local(),
+                                );
+                            }
+                        }
+                    }
+
+                    // Without a preamble, the text size is all there is
+                    if preamble.is_none() {
+                        preamble = text_size;
+                    }
+
+                    // The shown code starts with the preamble it is compiled with, if asked to
+                    let shown_preamble = preamble
+                        .as_deref()
+                        .filter(|_| settings.show_preamble_in_source && render && preamble_ok)
+                        .map(|preamble| preamble.trim_end().to_owned() + "\n")
+                        .filter(|preamble| preamble != "\n");
+                    let offset = shown_preamble
+                        .as_deref()
+                        .map_or(0, |shown| shown.lines().count());
+                    let shown = shown_preamble.unwrap_or_default() + &text;
+                    // Classes go to the wrapper of rendered blocks and to the `<pre>` of the others
+                    let classes = attrs.classes(&chapter.name);
+                    let (pre_class, wrapper_classes) =
+                        if (render && preamble_ok) || settings.bare_output {
+                            (None, classes)
+                        } else {
+                            (classes, None)
+                        };
+                    let mut html = if attrs.flag("nohighlight") {
+                        let html = plain_code(&shown, false, settings);
+                        match &pre_class {
+                            Some(class) => add_pre_class(&html, class),
+                            None => html,
+                        }
+                    } else {
+                        let emphasized = attrs
+                            .emphasized_lines(text.lines().count())
+                            .into_iter()
+                            .map(|line| line + offset)
+                            .collect();
+                        jobs.push(HighlightJob {
+                            emphasized,
+                            pre_class,
+                            ..HighlightJob::new(shown, &themes, &chapter.name)
+                        })
+                    };
+
+                    if render && preamble_ok {
+                        let alt_text = attrs.alt_text(&text).map(str::to_owned);
+                        let alt = alt_text.as_deref().map(escape_html);
+                        let location = BlockLocation {
+                            file: chapter
+                                .source_path
+                                .as_ref()
+                                .or(chapter.path.as_ref())
+                                .map(|path| slash_path(path))
+                                .unwrap_or_else(|| chapter.name.clone()),
+                            line: codeblock_line,
+                            preamble_lines: 0,
+                            wrap_lines: mode.wrap_lines(),
+                        };
+                        // Cache names change with Typst, the golden ones have to stay
+                        let golden_name = format!(
+                            "{}/block-{}",
+                            location.file.strip_suffix(".md").unwrap_or(&location.file),
+                            images.len() + 1
+                        );
+                        let request = RenderRequest {
+                            src: mode.wrap(text),
+                            dir: chapter_path.clone(),
+                            source_dir: source_dir.clone(),
+                            chapter: chapter.name.clone(),
+                            location,
+                            slug: settings.file_slug(
+                                &chapter.name,
+                                attrs.get("caption"),
+                                images.len() + 1,
+                            ),
+                            preamble,
+                            inputs: settings.block_inputs(&attrs),
+                        };
+                        let (file, input, err) = render_block(request, run);
+
+                        if settings.golden.is_some() {
+                            rendered.push((file.clone(), golden_name));
+                        }
+
+                        used.insert(file.clone());
+
+                        // The block is going to be the next one in `images`
+                        let block = images.len();
+                        compile_errors.extend(err.into_iter().map(|error| {
+                            error
+                                .map(move |error| error.map(|error| (Some(block), error)))
+                                .boxed_local()
+                        }));
+
+                        if settings.embed_source {
+                            embedded = Some(source_attribute(&input));
+                        }
+
+                        // Escaped for markdown figures too, where text goes to the output as is
+                        let caption = attrs.get("caption").map(|caption| {
+                            figure_number += 1;
+                            escape_html(&format!("Figure {figure_number}: {caption}"))
+                        });
+
+                        let width = attrs
+                            .parsed("width", &chapter.name, "a CSS length", |width| {
+                                is_css_length(width).then_some(width)
+                            })
+                            .unwrap_or_else(|| settings.max_width());
+
+                        let background = attrs
+                            .parsed("bg", &chapter.name, "a CSS color", |bg| {
+                                is_css_background(bg).then(|| format!("background: {bg}; "))
+                            })
+                            .unwrap_or_else(|| settings.image_background().to_owned());
+
+                        let scale = attrs
+                            .parsed("scale", &chapter.name, "a positive number", |scale| {
+                                scale.parse().ok().filter(|&scale| is_scale(scale))
+                            })
+                            .or(settings.scale);
+
+                        let align = attrs
+                            .parsed(
+                                "align",
+                                &chapter.name,
+                                "left, center or right",
+                                ImageAlign::parse,
+                            )
+                            .unwrap_or(settings.align);
+
+                        let index_entry = settings.emit_index.then(|| IndexEntry {
+                            chapter: chapter.name.clone(),
+                            path: chapter.path.as_deref().map(slash_path),
+                            hash: file.file_name().unwrap().to_string_lossy().into_owned(),
+                            files: vec![],
+                            caption: attrs.get("caption").map(str::to_owned),
+                            alt: alt_text,
+                        });
+
+                        let index = images.len();
+                        images.push(BlockImages {
+                            file,
+                            width: width.to_owned(),
+                            math: mode == TypstMode::Math,
+                            alt,
+                            background,
+                            scale,
+                            align,
+                            title: attrs.get("title").map(str::to_owned),
+                            pages: attrs.pages(&chapter.name),
+                            index: index_entry,
+                        });
+
+                        if settings.markdown_figures {
+                            figure = Some((index, caption));
+                        } else if let Some(caption) = caption {
+                            image_html += format!(
+                                r#"<figure style="margin: 0">{}<figcaption style="text-align: {}; font-style: italic;">{caption}</figcaption></figure>"#,
+                                placeholder(IMAGE_PLACEHOLDER, index),
+                                align.text_align()
+                            )
+                            .as_str();
+                        } else {
+                            image_html += &placeholder(IMAGE_PLACEHOLDER, index);
+                        }
+                    }
+                    // Without rendering, the code is shown anyway
+                    let hide_source =
+                        attrs.flag("hidesrc") && (!image_html.is_empty() || figure.is_some());
+                    if hide_source {
+                        html.clear();
+                    } else {
+                        html.insert_str(0, &attrs.file_label());
+                    }
+
+                    let embedded = embedded.unwrap_or_default();
+                    let margin = settings.block_spacing.margin();
+                    // Side by side columns are stacked when the page is too narrow for both
+                    let html = if attrs.get("layout") == Some("side")
+                        && !image_html.is_empty()
+                        && !hide_source
+                    {
+                        let classes = wrapper_classes
+                            .map(|classes| format!(" {classes}"))
+                            .unwrap_or_default();
+                        format!(
+                            r#"<div class="typst-side{classes}"{embedded} style="display: flex; flex-wrap: wrap; gap: 0.5em; align-items: flex-start; {margin}"><div class="typst-side-source" style="flex: 1 1 20em; min-width: 0">{html}</div><div class="typst-side-output" style="flex: 1 1 20em; min-width: 0">{image_html}</div></div>"#
+                        )
+                    } else {
+                        let class = class_attribute(wrapper_classes.as_deref());
+                        format!(
+                            r#"<div{class}{embedded} style="{margin}">{html}{image_html}</div>"#
+                        )
+                    };
+                    new_events.push(Event::Start(Tag::HtmlBlock));
+                    new_events.push(Event::Html(html.into()));
+                    // The HTML block has to be closed with a blank line, which tight lists and quotes
+                    // don't put between blocks, or the figure and text after it become a part of it
+                    new_events.push(Event::Html("\n\n".into()));
+                    new_events.push(Event::End(TagEnd::HtmlBlock));
+                    if let Some((index, caption)) = figure {
+                        new_events.push(Event::Start(Tag::Paragraph));
+                        new_events
+                            .push(Event::Text(placeholder(MARKDOWN_PLACEHOLDER, index).into()));
+                        new_events.push(Event::End(TagEnd::Paragraph));
+                        if let Some(caption) = caption {
+                            new_events.push(Event::Start(Tag::Paragraph));
+                            new_events.push(Event::Start(Tag::Emphasis));
+                            new_events.push(Event::Text(caption.into()));
+                            new_events.push(Event::End(TagEnd::Emphasis));
+                            new_events.push(Event::End(TagEnd::Paragraph));
+                        }
+                    }
+                    if settings.block_spacing.hard_break() {
+                        new_events.push(Event::HardBreak);
+                    }
+                    current_codeblock = None
+                }
+                None => new_events.push(event),
+            },
+            Event::Code(code) if settings.highlight_inline() => {
+                // With a prefix, only the marked spans are Typst
+                let src = match &settings.inline_prefix {
+                    Some(prefix) => code.strip_prefix(prefix.as_str()),
+                    None => Some(code.as_ref()),
+                };
+
+                match src {
+                    Some(src)
+                        if link_depth == 0
+                            || settings.inline_in_links == InlineInLinks::Highlight =>
+                    {
+                        let html = jobs.push(HighlightJob {
+                            inline: true,
+                            in_link: link_depth > 0,
+                            ..HighlightJob::new(src.to_owned(), themes, &chapter.name)
+                        });
+                        new_events.push(Event::InlineHtml(html.into()))
+                    }
+                    _ => new_events.push(Event::Code(code)),
+                }
+            }
+            Event::Text(ref s) => match current_codeblock {
+                Some((_, _, ref mut text)) => {
+                    text.push_str(s);
+                }
+                None => new_events.push(event),
+            },
+            ev => new_events.push(ev),
+        }
+    }
+
+    let prepared = PreparedChapter {
+        events: new_events.into_iter().map(Event::into_static).collect(),
+        rendered,
+        images,
+    };
+
+    Ok((prepared, compile_errors))
+}
+
+/// An error of a chapter, with the index of the rendered block in `PreparedChapter::images`
+/// if it is about one
+type BlockError = (Option<usize>, String);
+
+/// Inserts the rendered images into the chapter, `compile_errors` are the ones of its blocks
+fn finish_chapter(
+    chapter: &mut Chapter,
+    prepared: PreparedChapter,
+    compile_errors: Vec<BlockError>,
+    run: &RunState,
+    highlighted: &[String],
+) -> Result<()> {
+    let settings = run.settings;
+    let PreparedChapter {
+        events: new_events,
+        rendered,
+        images,
+    } = prepared;
+
+    // Directory of the page, relative to the book sources
+    let page_dir = chapter
+        .path
+        .as_ref()
+        .and_then(|p| p.parent())
+        .map(Path::to_path_buf)
+        .unwrap_or_default();
+
+    if settings.strict && !compile_errors.is_empty() {
+        let errors = compile_errors
+            .into_iter()
+            .map(|(_, error)| error)
+            .collect::<Vec<_>>();
+        return Err(anyhow!(errors.join("\n")));
+    }
+
+    // Errors of rendered blocks shown under them, by the index of the block
+    let mut block_errors = BTreeMap::<usize, Vec<String>>::new();
+    for (block, error) in compile_errors {
+        log::error!("{}", error.trim_end());
+        if let Some(block) = block.filter(|_| settings.show_errors_inline) {
+            block_errors.entry(block).or_default().push(error);
+        }
+    }
+
+    if let Some(golden) = &settings.golden {
+        let mismatches = rendered
+            .into_iter()
+            .flat_map(|(file, golden_name)| {
+                let dir = file.parent().unwrap().to_path_buf();
+                get_images(file, settings.format.extension()).map(move |name| {
+                    // The page number and extension, e.g. `-1.svg`
+                    let page = split_cache_name(&name).map_or("", |(_, _, page)| page);
+                    let golden = golden.join(format!("{golden_name}{page}"));
+                    check_golden(&dir, &name, &golden, settings)
+                })
+            })
+            .filter_map(Result::err)
+            .collect::<Vec<_>>();
+
+        if !mismatches.is_empty() {
+            return Err(anyhow!(
+                "Rendered images of chapter \"{}\" differ from golden ones:\n{:#?}",
+                chapter.name,
+                mismatches
+            ));
+        }
+    }
+
+    // Okay, all images are rendered now, so it's time to replace placeholders with true file names!
+
+    // Images of a rendered block, with its download links and errors
+    let block_html = |index: usize, block: &BlockImages| {
+        let file = &block.file;
+
+        let dir = file.parent().unwrap().to_path_buf();
+        let chapter_dir = dir.parent().unwrap().to_path_buf();
+        let downloads = downloads(file, &page_dir, settings);
+
+        let names = get_images(file.clone(), settings.format.extension()).collect::<Vec<_>>();
+        let pages = names.len();
+        let mut inner = block
+            .select_pages(names, &chapter.name)
+            .into_iter()
+            .map(|(page, name)| {
+                let thumb = settings
+                    .thumbnail
+                    .then(|| make_thumbnail(&dir, &name, settings.thumbnail_size()))
+                    .flatten();
+                let inline = (settings.inline_svg
+                    && settings.format == ImageFormat::Svg
+                    && thumb.is_none())
+                .then(|| inline_svg(&dir.join(&name)))
+                .flatten();
+                // The `<img>` of a `<picture>` is the PNG, the SVG is its source
+                let picture = settings
+                    .picture
+                    .then(|| picture_pages(&dir, &name, settings.format))
+                    .flatten();
+                let img_name = picture.as_ref().map_or(&name, |(_, png)| png);
+                let src =
+                    asset_url(&chapter_dir, &page_dir, "typst-img", img_name, settings);
+                let srcset = hidpi_srcset(&chapter_dir, &page_dir, img_name, settings);
+                let background = &block.background;
+                let alt = block.alt(page, pages);
+                let lazy = settings.lazy_loading();
+                let loading = if lazy { r#" loading="lazy""# } else { "" };
+                let dimensions = lazy.then(|| image_size(&dir.join(&name))).flatten();
+                // PNGs have `ppi` pixels per inch, CSS has 96
+                let scaled_width = block.scale.and_then(|scale| {
+                    let (width, _) = image_size(&dir.join(&name))?;
+                    let width = match settings.format {
+                        ImageFormat::Png => width as f64 * 96.0 / settings.ppi(),
+                        ImageFormat::Svg => width as f64,
+                    };
+                    Some((width * scale).round())
+                });
+                let size = if block.math {
+                    format!(
+                        "vertical-align: {};{}",
+                        escape_html(settings.math_vertical_align()),
+                        scaled_width
+                            .map(|width| format!(" width: {width}px; height: auto;"))
+                            .unwrap_or_default()
+                    )
+                } else if let Some(width) = scaled_width {
+                    format!("width: {width}px; max-width: 100%; height: auto;")
+                } else if dimensions.is_some() {
+                    // The height follows the scaled width, not the `height` attribute
+                    format!("max-width: {}; width: 100%; height: auto;", block.width)
+                } else {
+                    format!("max-width: {}; width: 100%;", block.width)
+                };
+                let dimensions = dimensions
+                    .map(|(width, height)| format!(r#" width="{width}" height="{height}""#))
+                    .unwrap_or_default();
+                let wrapper_bg = escape_html(settings.wrapper_bg());
+                let align = block.align.text_align();
+                let title = block
+                    .title
+                    .as_deref()
+                    .map(|title| format!(r#" title="{}""#, escape_html(title)))
+                    .unwrap_or_default();
+
+                match (thumb, inline) {
+                    // There is no `<img>`, so the box shows the tooltip
+                    (_, Some(svg)) => format!(
+                        r#"<div style="text-align: {align}; padding: 0.5em; background: {wrapper_bg};"{title}>
+                            {svg}
+                            </div>"#
+                    ),
+                    (Some(thumb), None) => {
+                        let thumb_size = lazy
+                            .then(|| image_size(&dir.join(&thumb)))
+                            .flatten()
+                            .map(|(width, height)| {
+                                format!(r#" width="{width}" height="{height}""#)
+                            })
+                            .unwrap_or_default();
+                        let thumb =
+                            asset_url(&chapter_dir, &page_dir, "typst-img", &thumb, settings);
+                        format!(
+                            r#"<div style="text-align: {align}; padding: 0.5em; background: {wrapper_bg};">
+                            <a href="{src}"><img align="middle" src="{thumb}"{loading}{thumb_size} alt="{alt}"{title} style="{}"></a>
+                            </div>"#,
+                            background.trim_end()
+                        )
+                    }
+                    (None, None) => {
+                        let img = format!(
+                            r#"<img align="middle" src="{src}"{srcset}{loading}{dimensions} alt="{alt}"{title} style="{background}{size}">"#
+                        );
+                        let img = match picture {
+                            Some((svg, _)) => format!(
+                                r#"<picture><source srcset="{}" type="image/svg+xml">{img}</picture>"#,
+                                asset_url(&chapter_dir, &page_dir, "typst-img", &svg, settings)
+                            ),
+                            None => img,
+                        };
+                        format!(
+                            r#"<div style="text-align: {align}; padding: 0.5em; background: {wrapper_bg};">
+                            {img}
+                            </div>"#
+                        )
+                    }
+                }
+            })
+            .collect::<String>();
+
+        if !downloads.is_empty() {
+            let links = downloads
+                .into_iter()
+                .map(|(label, url)| format!(r#"<a href="{url}" download>{label}</a>"#))
+                .collect::<Vec<_>>()
+                .join(" · ");
+            inner += &format!(r#"<div style="text-align: right; font-size: 0.8em;">{links}</div>"#);
+        }
+
+        for error in block_errors.get(&index).into_iter().flatten() {
+            inner += &format!(
+                r#"<div class="typst-error" style="border-left: 4px solid #d33; padding: 0.5em; background: var(--quote-bg);"><pre style="margin: 0; white-space: pre-wrap;">{}</pre></div>"#,
+                // A blank line would end the HTML block in the middle of the message
+                escape_html(error.trim_end()).replace('\n', "&#10;")
+            );
+        }
+
+        inner
+    };
+
+    let new_events = new_events
+        .into_iter()
+        .map(|e| match e {
+            Event::Html(s) if s.contains(HIGHLIGHT_PLACEHOLDER) => {
+                Event::Html(insert_highlighted(&s, highlighted).into())
+            }
+            Event::InlineHtml(s) if s.contains(HIGHLIGHT_PLACEHOLDER) => {
+                Event::InlineHtml(insert_highlighted(&s, highlighted).into())
+            }
+            e => e,
+        })
+        .flat_map(|e| match e {
+            Event::Text(s)
+                if let Some(block) = placeholder_index(&s, MARKDOWN_PLACEHOLDER)
+                    .and_then(|index| images.get(index)) =>
+            {
+                let file = &block.file;
+
+                let chapter_dir = file.parent().unwrap().parent().unwrap().to_path_buf();
+                let downloads = downloads(file, &page_dir, settings);
+
+                let names =
+                    get_images(file.clone(), settings.format.extension()).collect::<Vec<_>>();
+                let pages = names.len();
+                let mut events = block
+                    .select_pages(names, &chapter.name)
+                    .into_iter()
+                    .flat_map(|(page, name)| {
+                        [
+                            Event::Start(Tag::Image {
+                                link_type: LinkType::Inline,
+                                dest_url: asset_url(
+                                    &chapter_dir,
+                                    &page_dir,
+                                    "typst-img",
+                                    &name,
+                                    settings,
+                                )
+                                .into(),
+                                title: block.title.clone().unwrap_or_default().into(),
+                                id: "".into(),
+                            }),
+                            Event::Text(block.alt(page, pages).into()),
+                            Event::End(TagEnd::Image),
+                        ]
+                    })
+                    .collect::<Vec<_>>();
+
+                for (label, url) in downloads {
+                    events.extend([
+                        Event::HardBreak,
+                        Event::Start(Tag::Link {
+                            link_type: LinkType::Inline,
+                            dest_url: url.into(),
+                            title: "".into(),
+                            id: "".into(),
+                        }),
+                        Event::Text(label.into()),
+                        Event::End(TagEnd::Link),
+                    ]);
+                }
+
+                events
+            }
+            Event::Html(s) if s.contains(IMAGE_PLACEHOLDER) => {
+                let new_s = replace_placeholders(&s, IMAGE_PLACEHOLDER, |index| {
+                    images.get(index).map(|block| block_html(index, block))
+                });
+
+                vec![Event::Html(new_s.into())]
+            }
+            e => vec![e],
+        });
+
+    let style = matches!(*run.themes, Themes::Dual { .. }).then(|| {
+        [
+            Event::Start(Tag::HtmlBlock),
+            Event::Html(format!("{DUAL_THEMES_STYLE}\n").into()),
+            Event::End(TagEnd::HtmlBlock),
+        ]
+    });
+    let new_events = style.into_iter().flatten().chain(new_events);
+
+    let mut buf = String::with_capacity(chapter.content.len());
+    cmark(new_events.into_iter(), &mut buf)
+        .map_err(|err| anyhow!("Markdown serialization failed: {}", err))?;
+
+    // Without a stylesheet file, chapters with highlighted code carry the styles of the whole book
+    if settings.highlight_classes
+        && settings.highlight_css.is_none()
+        && buf.contains(" typst-theme-")
+    {
+        buf = format!("<style>\n{}</style>\n\n{buf}", settings.style_classes.css());
+    }
+
+    // Inline SVGs find the fonts by family in the page
+    if !settings.font_faces.is_empty() && buf.contains("<svg") {
+        buf = format!(
+            "<style>\n{}</style>\n\n{buf}",
+            font_face_css(&page_dir, settings)
+        );
+    }
+
+    if buf.contains(r#"class="clip-button typst-copy""#) {
+        buf = format!("{buf}\n\n{COPY_BUTTON_SCRIPT}\n");
+    }
+
+    chapter.content = buf;
+
+    Ok(())
+}
+
+fn codeblock_lang<'a>(
+    kind: &'a CodeBlockKind,
+    settings: &PreprocessSettings,
+    chapter: &str,
+) -> Option<&'a str> {
+    match kind {
+        CodeBlockKind::Fenced(kind) => {
+            if !kind.is_empty() {
+                Some(kind.as_ref())
+            } else {
+                if settings.warn_not_specified {
+                    log::warn!("Codeblock language not specified in {}", chapter)
+                }
+                settings.typst_default.then_some("typ")
+            }
+        }
+        // Indented prose would become Typst with `typst_default`, so they are separate
+        CodeBlockKind::Indented => settings.indented_default.then_some("typ"),
+    }
+}
+
+/// Whether `value` looks like a CSS color or another simple `background`,
+/// such as `#222`, `black`, `none` or `var(--bg)`
+fn is_css_background(value: &str) -> bool {
+    if let Some(hex) = value.strip_prefix('#') {
+        return matches!(hex.len(), 3 | 4 | 6 | 8) && hex.chars().all(|c| c.is_ascii_hexdigit());
+    }
+
+    // Function arguments can't have commas or spaces, they separate options
+    let (name, args) = match value.split_once('(') {
+        Some((name, args)) => (name, args.strip_suffix(')').unwrap_or("(")),
+        None => (value, ""),
+    };
+
+    !name.is_empty()
+        && name.chars().all(|c| c.is_ascii_alphabetic() || c == '-')
+        && args
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '%' | '/'))
+}
+
+/// Whether `value` looks like a length Typst accepts for pages, such as `600pt`, `1.5cm` or `auto`
+fn is_typst_length(value: &str) -> bool {
+    const UNITS: [&str; 5] = ["pt", "mm", "cm", "in", "em"];
+
+    if value == "auto" {
+        return true;
+    }
+
+    let number_end = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(number_end);
+
+    number.parse::<f64>().is_ok() && UNITS.contains(&unit)
+}
+
+/// Whether `value` is a length Typst accepts for text, such as `11pt`, pages may also be `auto`
+fn is_text_size(value: &str) -> bool {
+    value != "auto" && is_typst_length(value)
+}
+
+/// Whether `name` is a CSS class name that needs no escaping, such as `wide` or `my-diagram_2`
+fn is_class_name(name: &str) -> bool {
+    let name_start = name.trim_start_matches('-');
+    name_start
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// ` class="..."` for a wrapper, nothing without classes
+fn class_attribute(classes: Option<&str>) -> String {
+    classes
+        .map(|classes| format!(r#" class="{}""#, escape_html(classes)))
+        .unwrap_or_default()
+}
+
+/// Checks `scale`, a factor of the typeset size
+fn is_scale(value: f64) -> bool {
+    value.is_finite() && value > 0.0
+}
+
+/// Whether `value` looks like a CSS length, such as `600pt` or `80%`
+fn is_css_length(value: &str) -> bool {
+    const UNITS: [&str; 14] = [
+        "px", "pt", "pc", "cm", "mm", "in", "em", "rem", "ex", "ch", "vw", "vh", "%", "q",
+    ];
+
+    if value == "none" {
+        return true;
+    }
+
+    let number_end = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(number_end);
+
+    number.parse::<f64>().is_ok() && UNITS.contains(&unit.to_ascii_lowercase().as_str())
+}
+
+/// Options that are either set or not, such as `norender`
+const BLOCK_FLAGS: [&str; 6] = [
+    "norender",
+    "nopreamble",
+    "hidesrc",
+    "justify",
+    "nojustify",
+    "nohighlight",
+];
+
+/// Options with a value, such as `width=80%`, besides `input.<name>`
+const BLOCK_KEYS: [&str; 16] = [
+    "caption",
+    "alt",
+    "width",
+    "bg",
+    "hl",
+    "layout",
+    "preamble",
+    "theme",
+    "file",
+    "scale",
+    "align",
+    "theme_file",
+    "title",
+    "pages",
+    "textsize",
+    "class",
+];
+
+/// Values that may contain spaces, they go on until a comma
+const BLOCK_TEXT_KEYS: [&str; 7] = [
+    "caption",
+    "alt",
+    "theme",
+    "file",
+    "theme_file",
+    "title",
+    "class",
+];
+
+/// Info string of a block, such as `typ,norender,caption=Data flow`
+#[derive(Default)]
+struct BlockAttrs<'a> {
+    /// `typ` in `typ,norender` or `typ-norender`
+    language: &'a str,
+    flags: BTreeSet<&'a str>,
+    values: BTreeMap<&'a str, &'a str>,
+    /// Ranges of `hl=2-4,7`, the list goes on until the next option
+    hl: Vec<&'a str>,
+}
+
+impl<'a> BlockAttrs<'a> {
+    /// Options are separated with commas or spaces (and dashes, for flags),
+    /// unknown ones are ignored with a warning
+    fn parse(info: &'a str, chapter: &str) -> Self {
+        let language = language(info);
+        let mut attrs = BlockAttrs {
+            language,
+            ..Default::default()
+        };
+        let mut in_hl = false;
+
+        for part in info[language.len()..].split(',') {
+            let mut rest = part.trim_start_matches('-').trim_start();
+            while !rest.is_empty() {
+                let (token, after) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+
+                match token.split_once('=') {
+                    Some((key, _)) if BLOCK_TEXT_KEYS.contains(&key) => {
+                        let text = rest[key.len() + 1..].trim();
+                        if !text.is_empty() {
+                            attrs.values.insert(key, text);
+                        }
+                        in_hl = false;
+                        break;
+                    }
+                    _ if in_hl && token.chars().all(|c| c.is_ascii_digit() || c == '-') => {
+                        attrs.hl.push(token)
+                    }
+                    Some(("hl", ranges)) => {
+                        in_hl = true;
+                        attrs.hl.push(ranges)
+                    }
+                    Some((key, value))
+                        if BLOCK_KEYS.contains(&key) || key.starts_with("input.") =>
+                    {
+                        in_hl = false;
+                        attrs.values.insert(key, value);
+                    }
+                    None if token.split('-').all(|flag| BLOCK_FLAGS.contains(&flag)) => {
+                        in_hl = false;
+                        attrs.flags.extend(token.split('-'))
+                    }
+                    _ => {
+                        in_hl = false;
+                        log::warn!(
+                            "Ignoring unknown option `{token}` of a block in chapter \"{chapter}\""
+                        )
+                    }
+                }
+
+                rest = after.trim_start();
+            }
+        }
+
+        attrs
+    }
+
+    #[inline(always)]
+    fn flag(&self, name: &str) -> bool {
+        self.flags.contains(name)
+    }
+
+    /// Value of a `key=value` option, e.g. `setup.typ` of `preamble=setup.typ`
+    #[inline(always)]
+    fn get(&self, key: &str) -> Option<&'a str> {
+        self.values.get(key).copied()
+    }
+
+    /// `sys.inputs` from `input.key=value` options
+    fn inputs(&self) -> impl Iterator<Item = (&'a str, &'a str)> + '_ {
+        self.values
+            .iter()
+            .filter_map(|(key, value)| Some((key.strip_prefix("input.")?, *value)))
+    }
+
+    /// Header with the name of the file the code is from, given with `file=...`
+    fn file_label(&self) -> String {
+        self.get("file")
+            .map(|file| {
+                format!(
+                    r#"<div class="code-filename" style="font-family: monospace; font-size: 0.85em; padding: 0.2em 0.5em; background: var(--quote-bg);">{}</div>"#,
+                    escape_html(file)
+                )
+            })
+            .unwrap_or_default()
+    }
+
+    /// Description of a rendered block, from `alt=...`
+    /// or a `// alt: ...` comment on its first line
+    fn alt_text<'s>(&self, src: &'s str) -> Option<&'s str>
+    where
+        'a: 's,
+    {
+        self.get("alt").or_else(|| {
+            let alt = src.lines().next()?.trim().strip_prefix("//")?;
+            let alt = alt.trim_start().strip_prefix("alt:")?.trim();
+            (!alt.is_empty()).then_some(alt)
+        })
+    }
+
+    /// Line numbers from `hl=2-4,7`, invalid ones are skipped with a warning
+    fn emphasized_lines(&self, line_count: usize) -> BTreeSet<usize> {
+        let mut lines = BTreeSet::new();
+
+        for part in &self.hl {
+            let range: Option<(usize, usize)> = match part.split_once('-') {
+                Some((from, to)) => from.parse().ok().zip(to.parse().ok()),
+                None => part.parse().ok().map(|n| (n, n)),
+            };
+
+            match range {
+                Some((from, to)) if 1 <= from && from <= to && to <= line_count => {
+                    lines.extend(from..=to)
+                }
+                _ => log::warn!("Ignoring invalid line range `{part}` in hl"),
+            }
+        }
+
+        lines
+    }
+
+    /// CSS classes from `class=wide dark`, names that could break out of the attribute
+    /// or aren't CSS identifiers are skipped with a warning
+    fn classes(&self, chapter: &str) -> Option<String> {
+        let classes = self
+            .get("class")?
+            .split_whitespace()
+            .filter(|class| {
+                let valid = is_class_name(class);
+                if !valid {
+                    log::warn!(
+                        "Ignoring class `{class}` of a block in chapter \"{chapter}\", it isn't a CSS class name"
+                    );
+                }
+                valid
+            })
+            .collect::<Vec<_>>();
+
+        (!classes.is_empty()).then(|| classes.join(" "))
+    }
+
+    /// Value of a `key=value` option read with `parse`, an invalid one is ignored with a warning
+    /// that it isn't what is `expected`. `None` if the option isn't given or is invalid
+    fn parsed<T>(
+        &self,
+        key: &str,
+        chapter: &str,
+        expected: &str,
+        parse: impl FnOnce(&'a str) -> Option<T>,
+    ) -> Option<T> {
+        let value = self.get(key)?;
+        let parsed = parse(value);
+        if parsed.is_none() {
+            log::warn!(
+                "Ignoring {key}={value} of a block in chapter \"{chapter}\", it isn't {expected}"
+            );
+        }
+        parsed
+    }
+
+    /// Page numbers from `pages=2` or `pages=2-3`, an invalid range is ignored with a warning
+    fn pages(&self, chapter: &str) -> Option<RangeInclusive<usize>> {
+        self.parsed("pages", chapter, "a page range", |value| {
+            let (from, to) = match value.split_once('-') {
+                Some((from, to)) => (from.parse().ok()?, to.parse().ok()?),
+                None => {
+                    let page = value.parse().ok()?;
+                    (page, page)
+                }
+            };
+            (1 <= from && from <= to).then_some(from..=to)
+        })
+    }
+}
+
+/// How the source of a block is read by Typst
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum TypstMode {
+    /// `typ` or `typst`
+    Markup,
+    /// `typc`
+    Code,
+    /// `typm`
+    Math,
+}
+
+impl TypstMode {
+    /// Lines that `wrap` adds before the code
+    fn wrap_lines(self) -> usize {
+        match self {
+            TypstMode::Markup => 0,
+            TypstMode::Code => 1,
+            TypstMode::Math => MATH_PAGE.matches('\n').count(),
+        }
+    }
+
+    /// Source that Typst reads as markup
+    fn wrap(self, src: String) -> String {
+        match self {
+            TypstMode::Markup => src,
+            TypstMode::Code => format!("#{{\n{src}\n}}\n"),
+            TypstMode::Math => {
+                let src = src.trim();
+                // The formula may already be in dollars
+                if src.len() > 1 && src.starts_with('$') && src.ends_with('$') {
+                    format!("{MATH_PAGE}{src}\n")
+                } else {
+                    format!("{MATH_PAGE}$ {src} $\n")
+                }
+            }
+        }
+    }
+}
+
+/// Language in the info string of a block, e.g. `typ` in `typ,norender` or `typ-nopreamble`
+fn language(lang: &str) -> &str {
+    lang.split([',', ' ', '-']).next().unwrap_or_default()
+}
+
+/// Mode of a Typst block, from the language in its info string
+fn typst_mode(lang: &str) -> Option<TypstMode> {
+    match language(lang) {
+        "typ" | "typst" => Some(TypstMode::Markup),
+        "typc" => Some(TypstMode::Code),
+        "typm" => Some(TypstMode::Math),
+        _ => None,
+    }
+}
+
+/// `html` with placeholders of `HighlightJobs` replaced by the highlighted snippets
+fn insert_highlighted(html: &str, highlighted: &[String]) -> String {
+    replace_placeholders(html, HIGHLIGHT_PLACEHOLDER, |index| {
+        highlighted.get(index).cloned()
+    })
+}
+
+/// `<{name}-{nonce}-{index}>`, a tag for something that is only known later in the run.
+/// The nonce is new on every run, so that text of the book can't pass for a placeholder
+fn placeholder(name: &str, index: usize) -> String {
+    format!("<{name}-{}-{index}>", *PLACEHOLDER_NONCE)
+}
+
+/// Index of `s` if it is a placeholder named `name` and nothing else
+fn placeholder_index(s: &str, name: &str) -> Option<usize> {
+    s.strip_prefix(&format!("<{name}-{}-", *PLACEHOLDER_NONCE))?
+        .strip_suffix('>')?
+        .parse()
+        .ok()
+}
+
+/// `s` with placeholders named `name` replaced by what `insert` gives for their index,
+/// the ones it gives nothing for are left as they are
+fn replace_placeholders(
+    s: &str,
+    name: &str,
+    mut insert: impl FnMut(usize) -> Option<String>,
+) -> String {
+    let prefix = format!("<{name}-{}-", *PLACEHOLDER_NONCE);
+
+    let mut replaced = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find(&prefix) {
+        let after = &rest[start + prefix.len()..];
+        let found = after
+            .split_once('>')
+            .and_then(|(index, after)| Some((insert(index.parse().ok()?)?, after)));
+        match found {
+            Some((inserted, after)) => {
+                replaced.push_str(&rest[..start]);
+                replaced.push_str(&inserted);
+                rest = after;
+            }
+            None => {
+                replaced.push_str(&rest[..start + prefix.len()]);
+                rest = after;
+            }
+        }
+    }
+    replaced.push_str(rest);
+
+    replaced
+}
+
+/// Escapes text and double-quoted attribute values
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// ` data-typst-src="..."` with the source given to Typst. Besides the escaped characters,
+/// line breaks are `&#10;`, so that blank lines don't end the HTML block in the markdown
+fn source_attribute(input: &str) -> String {
+    let escaped = escape_html(input)
+        .replace('\r', "&#13;")
+        .replace('\n', "&#10;");
+    format!(r#" data-typst-src="{escaped}""#)
+}
+
+/// Matches a `/`-separated path against a glob: `*` and `?` don't match `/`, `**` matches anything
+fn glob_match(pattern: &str, path: &str) -> bool {
+    fn matches(pattern: &[char], path: &[char]) -> bool {
+        match pattern {
+            [] => path.is_empty(),
+            ['*', '*', rest @ ..] => {
+                // `dir/**/*.md` also matches files right in `dir`
+                (rest.first() == Some(&'/') && matches(&rest[1..], path))
+                    || (0..=path.len()).any(|i| matches(rest, &path[i..]))
+            }
+            ['*', rest @ ..] => (0..=path.len())
+                .take_while(|&i| i == 0 || path[i - 1] != '/')
+                .any(|i| matches(rest, &path[i..])),
+            ['?', rest @ ..] => {
+                matches!(path, [c, tail @ ..] if *c != '/' && matches(rest, tail))
+            }
+            [p, rest @ ..] => matches!(path, [c, tail @ ..] if c == p && matches(rest, tail)),
+        }
+    }
+
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let path = path.chars().collect::<Vec<_>>();
+    matches(&pattern, &path)
+}
+
+/// Lowercase words of `text` joined with dashes, at most `MAX_SLUG_LEN` characters long
+fn slugify(text: &str) -> String {
+    let mut slug = String::new();
+    for word in text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+    {
+        if !slug.is_empty() {
+            slug.push('-');
+        }
+        slug.extend(word.chars().flat_map(char::to_lowercase));
+    }
+
+    match slug.char_indices().nth(MAX_SLUG_LEN) {
+        Some((end, _)) => slug[..end].trim_end_matches('-').to_owned(),
+        None => slug,
+    }
+}
+
+/// Contents of `<chapter>.preamble.typ` next to the chapter source,
+/// or of `preamble.typ` in its directory if there is no such file
+fn chapter_preamble(chapter: &Chapter, build_dir: &Path) -> String {
+    let Some(source) = chapter.source_path.as_ref().or(chapter.path.as_ref()) else {
+        return String::new();
+    };
+    let source = build_dir.join(source);
+
+    let own = source
+        .file_stem()
+        .map(|stem| source.with_file_name(stem.to_string_lossy().into_owned() + ".preamble.typ"));
+    let shared = source.with_file_name("preamble.typ");
+
+    own.into_iter()
+        .chain(iter::once(shared))
+        .find_map(|path| fs::read_to_string(path).ok())
+        .map(|preamble| preamble + "\n")
+        .unwrap_or_default()
+}
+
+/// A font of `embed_fonts`, converted to WOFF2
+struct FontFace {
+    family: String,
+    weight: u16,
+    italic: bool,
+    /// Name of the file in `typst-fonts`
+    file: String,
+}
+
+/// Converts the TrueType and OpenType fonts in `dirs` (searched recursively, as Typst does)
+/// to WOFF2 files in `out`. Files are named after the hash of the font, so each is converted once.
+/// Fonts that can't be read or converted are skipped with a warning
+fn convert_fonts(dirs: &[PathBuf], out: &Path) -> Vec<FontFace> {
+    fn font_files(dir: &Path, files: &mut Vec<PathBuf>) {
+        for entry in fs::read_dir(dir).into_iter().flatten().flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                font_files(&path, files);
+            } else if path.extension().is_some_and(|extension| {
+                extension.eq_ignore_ascii_case("ttf") || extension.eq_ignore_ascii_case("otf")
+            }) {
+                files.push(path);
+            }
+        }
+    }
+
+    let mut files = vec![];
+    for dir in dirs {
+        font_files(dir, &mut files);
+    }
+    files.sort();
+
+    files
+        .into_iter()
+        .filter_map(|path| {
+            let data = fs::read(&path).ok()?;
+            let Ok(face) = ttf_parser::Face::parse(&data, 0) else {
+                log::warn!("Can't read font {}, it isn't embedded", path.display());
+                return None;
+            };
+            let family = [
+                ttf_parser::name_id::TYPOGRAPHIC_FAMILY,
+                ttf_parser::name_id::FAMILY,
+            ]
+            .into_iter()
+            .find_map(|id| {
+                face.names()
+                    .into_iter()
+                    .filter(|name| name.name_id == id)
+                    .find_map(|name| name.to_string())
+            })?;
+
+            let stem = slugify(&path.file_stem()?.to_string_lossy());
+            let hash = format!("{:x}", Sha256::digest(&data));
+            let file = format!("{stem}-{}.woff2", &hash[..12]);
+            let target = out.join(&file);
+            if !target.exists() {
+                let written = ttf2woff2::encode(&data, ttf2woff2::BrotliQuality::default())
+                    .map_err(|e| e.to_string())
+                    .and_then(|woff2| {
+                        fs::create_dir_all(out).map_err(|e| e.to_string())?;
+                        fs::write(&target, woff2).map_err(|e| e.to_string())
+                    });
+                if let Err(e) = written {
+                    log::warn!(
+                        "Can't convert font {}, it isn't embedded: {e}",
+                        path.display()
+                    );
+                    return None;
+                }
+            }
+
+            Some(FontFace {
+                family,
+                weight: face.weight().to_number(),
+                italic: face.is_italic(),
+                file,
+            })
+        })
+        .collect()
+}
+
+/// `@font-face` rules of `embed_fonts` for a page in `page_dir`
+fn font_face_css(page_dir: &Path, settings: &PreprocessSettings) -> String {
+    settings
+        .font_faces
+        .iter()
+        .map(|face| {
+            format!(
+                "@font-face {{ font-family: \"{}\"; src: url(\"{}\") format(\"woff2\"); font-weight: {}; font-style: {}; }}\n",
+                face.family.replace(['"', '\\'], ""),
+                asset_url(
+                    &settings.cache_dir,
+                    page_dir,
+                    "typst-fonts",
+                    &face.file,
+                    settings,
+                ),
+                face.weight,
+                if face.italic { "italic" } else { "normal" },
+            )
+        })
+        .collect()
+}
+
+/// Checks whether files can be created in `dir` by creating one and removing it again.
+/// Unlike the permissions of its metadata, this knows the user, ACLs and read-only mounts
+fn is_writable(dir: &Path) -> bool {
+    let probe = dir.join(format!(".typst-highlight-probe-{}", std::process::id()));
+    let writable = fs::File::create_new(&probe).is_ok();
+    if writable {
+        let _ = fs::remove_file(probe);
+    }
+    writable
+}
+
+/// Where mdBook writes the HTML book, every renderer gets a directory of its own if there are several
+fn html_dir(ctx: &PreprocessorContext) -> PathBuf {
+    let build_dir = ctx.root.join(&ctx.config.build.build_dir);
+    let outputs = ctx
+        .config
+        .outputs::<serde::de::IgnoredAny>()
+        .map_or(0, |outputs| outputs.len());
+    if outputs > 1 {
+        build_dir.join("html")
+    } else {
+        build_dir
+    }
+}
+
+/// `to` relative to the directory `from`, going up with `..` where needed.
+/// `None` if they have nothing in common, e.g. are on different Windows drives
+fn relative_path(from: &Path, to: &Path) -> Option<PathBuf> {
+    let from = from.components().collect::<Vec<_>>();
+    let to = to.components().collect::<Vec<_>>();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+    // Going up through `..` or out of a drive can't be undone with more `..`
+    if common == 0
+        || from[common..]
+            .iter()
+            .any(|c| !matches!(c, std::path::Component::Normal(_)))
+    {
+        return None;
+    }
+    Some(
+        std::iter::repeat_n(std::path::Component::ParentDir, from.len() - common)
+            .chain(to[common..].iter().copied())
+            .collect(),
+    )
+}
+
+/// Whether a flag from the environment is on, it is unless it's unset, empty or `0`
+fn env_flag(value: Option<OsString>) -> bool {
+    value.is_some_and(|value| !value.is_empty() && value != "0")
+}
+
+/// `path` with `/` as the separator on every platform. Backslashes are separators too,
+/// as in paths of a `SUMMARY.md` written on Windows, even where they could be in file names
+fn slash_path(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+        .replace('\\', "/")
+}
+
+/// URL of a file saved into `kind` (`typst-img` or `typst-src`) in `dir`, for the page in `page_dir`.
+/// If the files are kept outside the book, they are embedded as data URLs.
+/// URLs are joined from path components with `/`, so that Windows separators don't leak into them
+fn asset_url(
+    dir: &Path,
+    page_dir: &Path,
+    kind: &str,
+    name: &str,
+    settings: &PreprocessSettings,
+) -> String {
+    // The files are linked to relative to the book sources
+    let (root, root_url) = match (&settings.out_dir, &settings.out_dir_url) {
+        (Some(out_dir), Some(url)) => (out_dir, url.as_str()),
+        _ => (&settings.src_dir, ""),
+    };
+    if !settings.embed_assets
+        && let Ok(relative) = dir.strip_prefix(root)
+    {
+        let components = |path: &Path| {
+            path.components()
+                .map(|c| c.as_os_str().to_string_lossy().into_owned())
+                .collect::<Vec<_>>()
+        };
+        // Up from the page to the closest common directory, then down into `dir`
+        let page = components(page_dir);
+        let target = root_url
+            .split('/')
+            .filter(|c| !c.is_empty())
+            .map(str::to_owned)
+            .chain(components(relative))
+            .collect::<Vec<_>>();
+        let common = page.iter().zip(&target).take_while(|(a, b)| a == b).count();
+
+        let up = "../".repeat(page.len() - common);
+        let down = target[common..]
+            .iter()
+            .map(|c| c.to_owned() + "/")
+            .collect::<String>();
+        return format!("{up}{down}{kind}/{name}");
+    }
+
+    if !settings.embed_assets {
+        return format!("{kind}/{name}");
+    }
+
+    let mime = match Path::new(name).extension().and_then(|e| e.to_str()) {
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("pdf") => "application/pdf",
+        Some("woff2") => "font/woff2",
+        _ => "text/plain",
+    };
+
+    match fs::read(dir.join(kind).join(name)) {
+        Ok(data) => format!("data:{mime};base64,{}", BASE64.encode(data)),
+        Err(_) => format!("{kind}/{name}"),
+    }
+}
+
+/// Contents of a rendered SVG to put right into the page. Ids are prefixed with the hash
+/// and page of the file (see `prefix_ids`), so that glyphs of several images on one page don't clash
+fn inline_svg(path: &Path) -> Option<String> {
+    let svg = fs::read_to_string(path).ok()?;
+    // A blank line would end the HTML block in markdown
+    let svg = svg[svg.find("<svg")?..]
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    // `{hash}-{n}.svg` gives ids that are unique even in `print.html`, where all chapters meet
+    let name = path.file_stem()?.to_string_lossy();
+    let (_, hash, page) = split_cache_name(&name)?;
+    let prefix = format!("typst-{}{}-", &hash[..12], page);
+
+    Some(prefix_ids(
+        &svg.replacen("<svg", r#"<svg style="max-width: 100%; height: auto;""#, 1),
+        &prefix,
+    ))
+}
+
+/// Prefixes `id="..."` attributes of an SVG and references to them:
+/// `href="#..."` (also `xlink:href`) and `url(#...)`
+fn prefix_ids(svg: &str, prefix: &str) -> String {
+    let mut res = String::with_capacity(svg.len());
+    let mut rest = svg;
+
+    while let Some(start) = rest.find(['i', 'h', 'u']) {
+        let (before, from) = rest.split_at(start);
+        res.push_str(before);
+
+        // Attributes have to start a word, so that e.g. `data-id` stays as it is
+        let attribute = res
+            .chars()
+            .next_back()
+            .is_some_and(|c| c.is_ascii_whitespace() || c == ':');
+        let reference = ["id=\"", "href=\"#"]
+            .into_iter()
+            .filter(|_| attribute)
+            .chain(iter::once("url(#"))
+            .find(|pattern| from.starts_with(pattern));
+
+        match reference {
+            Some(pattern) => {
+                res.push_str(pattern);
+                res.push_str(prefix);
+                rest = &from[pattern.len()..];
+            }
+            None => {
+                res.push_str(&from[..1]);
+                rest = &from[1..];
+            }
+        }
+    }
+    res.push_str(rest);
+
+    res
+}
+
+/// Names of the SVG and PNG of the page `name` in `format`, if both are there
+fn picture_pages(dir: &Path, name: &str, format: ImageFormat) -> Option<(String, String)> {
+    let base = name.strip_suffix(format.extension())?;
+    let (svg, png) = (format!("{base}svg"), format!("{base}png"));
+    (dir.join(&svg).exists() && dir.join(&png).exists()).then_some((svg, png))
+}
+
+/// ` srcset="..."` of a PNG page, if its 2x version was rendered
+fn hidpi_srcset(
+    chapter_dir: &Path,
+    page_dir: &Path,
+    name: &str,
+    settings: &PreprocessSettings,
+) -> String {
+    // `{hash}-{n}.png` has its 2x version in `{hash}-2x-{n}.png`
+    let hidpi = match split_cache_name(name) {
+        Some((base, _, page)) if settings.srcset() => format!("{base}-2x{page}"),
+        _ => return String::new(),
+    };
+    if !chapter_dir.join("typst-img").join(&hidpi).exists() {
+        return String::new();
+    }
+
+    format!(
+        r#" srcset="{} 1x, {} 2x""#,
+        asset_url(chapter_dir, page_dir, "typst-img", name, settings),
+        asset_url(chapter_dir, page_dir, "typst-img", &hidpi, settings)
+    )
+}
+
+/// Labels and URLs of downloadable files for the rendered file, see `render_block`
+fn downloads(
+    rendered: &Path,
+    page_dir: &Path,
+    settings: &PreprocessSettings,
+) -> Vec<(String, String)> {
+    let chapter_dir = rendered.parent().unwrap().parent().unwrap();
+    let base = rendered.file_name().unwrap().to_string_lossy();
+    let mut downloads = vec![];
+
+    for format in settings.extra_formats() {
+        let label = format.label();
+        if format == DownloadFormat::Pdf {
+            let pdf = base.clone().into_owned() + ".pdf";
+            if rendered.with_file_name(&pdf).exists() {
+                downloads.push((
+                    format!("Download {label}"),
+                    asset_url(chapter_dir, page_dir, "typst-img", &pdf, settings),
+                ));
+            }
+            continue;
+        }
+
+        let names = get_images(rendered.to_path_buf(), format.extension()).collect::<Vec<_>>();
+        let pages = names.len();
+        for (page, name) in names.into_iter().enumerate() {
+            let label = if pages > 1 {
+                format!("Download {label} (page {})", page + 1)
+            } else {
+                format!("Download {label}")
+            };
+            downloads.push((
+                label,
+                asset_url(chapter_dir, page_dir, "typst-img", &name, settings),
+            ));
+        }
+    }
+
+    if settings.source_download {
+        let source = base.into_owned() + ".typ";
+        downloads.push((
+            "Download .typ".to_owned(),
+            asset_url(chapter_dir, page_dir, "typst-src", &source, settings),
+        ));
+    }
+
+    downloads
+}
+
+/// Writes a copy of the rendered SVG scaled down to fit into `max_size` points. Only its size
+/// changes, the drawing is as big as the full image's.
+/// Returns the name of the thumbnail, or `None` if it couldn't be made.
+fn make_thumbnail(dir: &Path, name: &str, max_size: f64) -> Option<String> {
+    let thumb = name.strip_suffix(".svg")?.to_owned() + "-thumb.svg";
+    let thumb_path = dir.join(&thumb);
+
+    if thumb_path.exists() {
+        return Some(thumb);
+    }
+
+    let svg = fs::read_to_string(dir.join(name)).ok()?;
+
+    let tag_start = svg.find("<svg")?;
+    let tag_end = tag_start + svg[tag_start..].find('>')?;
+    let tag = &svg[tag_start..tag_end];
+
+    let (width_attr, width) = svg_length(tag, "width")?;
+    let (height_attr, height) = svg_length(tag, "height")?;
+    let scale = (max_size / width.max(height)).min(1.0);
+
+    let new_tag = tag
+        .replacen(width_attr, &format!(r#" width="{}pt""#, width * scale), 1)
+        .replacen(
+            height_attr,
+            &format!(r#" height="{}pt""#, height * scale),
+            1,
+        );
+
+    let content = svg[..tag_start].to_owned() + &new_tag + &svg[tag_end..];
+    match fs::write(&thumb_path, content) {
+        Ok(()) => Some(thumb),
+        Err(e) => {
+            log::warn!("Can't write thumbnail {}: {}", thumb_path.display(), e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mdbook_markdown::pulldown_cmark::html::push_html;
+    use mdbook_preprocessor::book::BookItem;
+    use mdbook_preprocessor::config::Config;
+    use std::ffi::OsStr;
+    use std::sync::Mutex;
+    use std::thread::ThreadId;
+
+    /// Messages logged at `warn` and above by all tests with their threads,
+    /// see `TestBook::warnings`
+    static LOGGED: Mutex<Vec<(ThreadId, String)>> = Mutex::new(Vec::new());
+
+    struct TestLogger;
+
+    impl log::Log for TestLogger {
+        fn enabled(&self, metadata: &log::Metadata) -> bool {
+            metadata.level() <= log::Level::Warn
+        }
+
+        fn log(&self, record: &log::Record) {
+            if self.enabled(record.metadata()) {
+                LOGGED
+                    .lock()
+                    .unwrap()
+                    .push((thread::current().id(), record.args().to_string()));
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    /// A book in a fresh directory for end-to-end tests. Its chapters are named after it,
+    /// like its directory, so that its warnings can be told from those of other tests
+    struct TestBook {
+        name: &'static str,
+        root: PathBuf,
+        /// Stub `typst`, if the book is rendered
+        typst: Option<PathBuf>,
+    }
+
+    impl TestBook {
+        /// An empty book that isn't rendered, `name` keeps tests running at once apart
+        fn new(name: &'static str) -> Self {
+            static LOGGER: std::sync::Once = std::sync::Once::new();
+            LOGGER.call_once(|| {
+                log::set_logger(&TestLogger).unwrap();
+                log::set_max_level(log::LevelFilter::Warn);
+            });
+
+            let root = std::env::temp_dir()
+                .join("mdbook-typst-highlight-tests")
+                .join(format!("{name}-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&root);
+            fs::create_dir_all(root.join("src")).unwrap();
+            TestBook {
+                name,
+                root,
+                typst: None,
+            }
+        }
+
+        /// A book rendered by a stub `typst` running `script`, see `stub_typst`
+        #[cfg(unix)]
+        fn with_typst(name: &'static str, script: &str) -> Self {
+            let mut book = Self::new(name);
+            book.stub_typst(script);
+            book
+        }
+
+        /// Replaces `typst` with a stub that prints its version (`typst 0.13.1` unless changed with
+        /// `set_typst_version`) and runs `script` for `typst c`,
+        /// after reading the source from stdin like Typst does, see `typst_input`
+        #[cfg(unix)]
+        fn stub_typst(&mut self, script: &str) {
+            use std::os::unix::fs::PermissionsExt;
+
+            let path = self.root.join("typst");
+            fs::write(
+                &path,
+                format!(
+                    "#!/bin/sh\nif [ \"$1\" = --version ]; then cat \"$0-version\" 2>/dev/null || echo 'typst 0.13.1'; exit 0; fi\ncat >> \"$0-input\"\n{script}\n"
+                ),
+            )
+            .unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+            self.typst = Some(path);
+        }
+
+        /// Writes `content` to `path` in the book's directory
+        fn write(&self, path: &str, content: &str) {
+            let path = self.root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+
+        /// Makes the stub `typst` print `version` for `typst --version`
+        #[cfg(unix)]
+        fn set_typst_version(&self, version: &str) {
+            fs::write(self.root.join("typst-version"), format!("{version}\n")).unwrap();
+        }
+
+        /// Runs the preprocessor on `book` with `settings`, after `render` and `typst_path`
+        /// if the book is rendered
+        fn run(&self, settings: &str, book: Book) -> Result<Book> {
+            let typst = self
+                .typst
+                .as_ref()
+                .map(|typst| format!("render = true\ntypst_path = {typst:?}\n"))
+                .unwrap_or_default();
+            let config =
+                format!("[preprocessor.typst-highlight]\n{typst}{settings}").parse::<Config>()?;
+            let ctx = PreprocessorContext::new(self.root.clone(), config, "html".to_owned());
+            TypstHighlight.run(&ctx, book)
+        }
+
+        /// HTML of chapters with their paths in the sources and contents
+        fn chapters(&self, settings: &str, chapters: &[(&str, &str)]) -> Result<Vec<String>> {
+            let mut book = Book::new();
+            for (path, content) in chapters {
+                book.push_item(Chapter::new(self.name, content.to_string(), *path, vec![]));
+            }
+
+            let mut html = vec![];
+            self.run(settings, book)?.for_each_chapter_mut(|chapter| {
+                let mut chapter_html = String::new();
+                push_html(
+                    &mut chapter_html,
+                    new_cmark_parser(&chapter.content, &Default::default()),
+                );
+                html.push(chapter_html);
+            });
+            Ok(html)
+        }
+
+        /// Markdown of the book with one chapter, `test.md`, as the preprocessor leaves it
+        fn markdown(&self, settings: &str, content: &str) -> Result<String> {
+            let mut book = Book::new();
+            book.push_item(Chapter::new(
+                self.name,
+                content.to_owned(),
+                "test.md",
+                vec![],
+            ));
+
+            let mut markdown = String::new();
+            self.run(settings, book)?
+                .for_each_chapter_mut(|chapter| markdown = chapter.content.clone());
+            Ok(markdown)
+        }
+
+        /// HTML of the book with one chapter, `test.md`
+        fn html(&self, settings: &str, content: &str) -> Result<String> {
+            Ok(self.chapters(settings, &[("test.md", content)])?.remove(0))
+        }
+
+        /// Error of a run that fails
+        fn error(&self, settings: &str, content: &str) -> String {
+            self.html(settings, content).unwrap_err().to_string()
+        }
+
+        /// Sources the stub `typst` read since the last call
+        fn typst_input(&self) -> String {
+            let path = self.root.join("typst-input");
+            let input = fs::read_to_string(&path).unwrap_or_default();
+            let _ = fs::remove_file(path);
+            input
+        }
+
+        /// Names of the rendered images and PDFs in `typst-img` directories of the sources
+        fn images(&self) -> Vec<String> {
+            fn walk(dir: &Path, images: &mut Vec<String>) {
+                for entry in fs::read_dir(dir).into_iter().flatten().flatten() {
+                    let path = entry.path();
+                    if path.is_dir() {
+                        walk(&path, images);
+                    } else if dir.ends_with("typst-img")
+                        && path.extension().is_some_and(|extension| {
+                            ["svg", "png", "pdf"].map(OsStr::new).contains(&extension)
+                        })
+                    {
+                        images.push(entry.file_name().to_string_lossy().into_owned());
+                    }
+                }
+            }
+
+            let mut images = vec![];
+            walk(&self.root.join("src"), &mut images);
+            images.sort();
+            images
+        }
+
+        /// Warnings logged so far by the test's thread, or by others about the book's chapters
+        /// or files
+        fn warnings(&self) -> Vec<String> {
+            let logged = LOGGED.lock().unwrap();
+            logged
+                .iter()
+                .filter(|(thread, message)| {
+                    *thread == thread::current().id() || message.contains(self.name)
+                })
+                .map(|(_, message)| message.clone())
+                .collect()
+        }
+    }
+
+    /// `html` without its tags, with entities left as they are
+    fn text(html: &str) -> String {
+        let mut text = String::new();
+        let mut in_tag = false;
+        for c in html.chars() {
+            match c {
+                '<' => in_tag = true,
+                '>' => in_tag = false,
+                c if !in_tag => text.push(c),
+                _ => {}
+            }
+        }
+        text
+    }
+
+    /// Stub script that writes an SVG to the output, which is the last argument
+    #[cfg(unix)]
+    const STUB_RENDER: &str = r#"for arg; do out="$arg"; done
+out=$(echo "$out" | sed 's/{p}/1/; s/{n}/1/')
+mkdir -p "$(dirname "$out")"
+echo '<svg width="10pt" height="10pt"></svg>' > "$out""#;
+
+    /// Stub script that writes three pages, each SVG with its number in a comment
+    #[cfg(unix)]
+    const STUB_THREE_PAGES: &str = r#"for arg; do out="$arg"; done
+for p in 1 2 3; do
+page=$(echo "$out" | sed "s/{p}/$p/; s/{n}/$p/")
+mkdir -p "$(dirname "$page")"
+echo "<svg width=\"10pt\" height=\"10pt\"><!-- $p --></svg>" > "$page"
+done"#;
+
+    /// Chapter with one rendered block
+    const RENDERED: &str = "```typ\n= Title\n```\n";
+
+    #[test]
+    fn block_attrs_parse_flags_and_values() {
+        let attrs = BlockAttrs::parse("typ,norender,caption=Data flow,width=80%", "Test");
+        assert_eq!(attrs.language, "typ");
+        assert!(attrs.flag("norender"));
+        assert!(!attrs.flag("nopreamble"));
+        assert_eq!(attrs.get("caption"), Some("Data flow"));
+        assert_eq!(attrs.get("width"), Some("80%"));
+
+        // Flags may be joined with dashes, options separated with spaces
+        let attrs = BlockAttrs::parse("typ-norender-nopreamble hl=2-4,7 bg=red", "Test");
+        assert!(attrs.flag("norender") && attrs.flag("nopreamble"));
+        assert_eq!(attrs.hl, ["2-4", "7"]);
+        assert_eq!(attrs.get("bg"), Some("red"));
+
+        let attrs = BlockAttrs::parse("typ,input.name=World,input.n=2", "Test");
+        assert_eq!(
+            attrs.inputs().collect::<Vec<_>>(),
+            [("n", "2"), ("name", "World")]
+        );
+
+        // Unknown options are ignored, a caption mentioning a flag doesn't set it
+        let attrs = BlockAttrs::parse("typ,bogus,caption=norender,width", "Test");
+        assert!(attrs.flags.is_empty());
+        assert_eq!(attrs.get("caption"), Some("norender"));
+        assert_eq!(attrs.get("width"), None);
+    }
+
+    #[test]
+    fn typst_mode_matches_only_typst_languages() {
+        assert!(matches!(typst_mode("typ"), Some(TypstMode::Markup)));
+        assert!(matches!(
+            typst_mode("typst,norender"),
+            Some(TypstMode::Markup)
+        ));
+        assert!(matches!(typst_mode("typc"), Some(TypstMode::Code)));
+        assert!(matches!(
+            typst_mode("typm-nopreamble"),
+            Some(TypstMode::Math)
+        ));
+        for lang in ["typescript", "prototype", "rust,typ", ""] {
+            assert!(typst_mode(lang).is_none(), "{lang}");
+        }
+    }
+
+    #[test]
+    fn escape_html_makes_markup_inert() {
+        assert_eq!(
+            escape_html(r#"<script>alert("x") & </script>"#),
+            "&lt;script&gt;alert(&quot;x&quot;) &amp; &lt;/script&gt;"
+        );
+
+        let theme = theme(DEFAULT_THEME).unwrap();
+        let html =
+            highlight_typst("<script>alert(1)</script>", &HighlightOptions::new(&theme)).unwrap();
+        assert!(!html.contains("<script>"));
+    }
+
+    #[test]
+    fn default_highlight_options_use_the_default_theme() {
+        let theme = theme(DEFAULT_THEME).unwrap();
+        let src = "#let x = [*bold*] // note";
+        assert_eq!(
+            highlight_typst(src, &HighlightOptions::default()).unwrap(),
+            highlight_typst(src, &HighlightOptions::new(&theme)).unwrap()
+        );
+    }
+
+    #[test]
+    fn prefix_ids_prefixes_ids_and_references() {
+        let svg =
+            r##"<svg><path id="g1" data-id="x"/><use href="#g1"/><g clip-path="url(#c1)"/></svg>"##;
+        assert_eq!(
+            prefix_ids(svg, "abc-"),
+            r##"<svg><path id="abc-g1" data-id="x"/><use href="#abc-g1"/><g clip-path="url(#abc-c1)"/></svg>"##
+        );
+        // `xlink:href` is an attribute too
+        assert_eq!(
+            prefix_ids(r##"<use xlink:href="#g1"/>"##, "p"),
+            r##"<use xlink:href="#pg1"/>"##
+        );
+    }
+
+    #[test]
+    fn paths_are_joined_with_slashes() {
+        let path = ["guide", "intro", "typst-img"].iter().collect::<PathBuf>();
+        assert_eq!(slash_path(&path), "guide/intro/typst-img");
+        assert_eq!(
+            slash_path(Path::new(r"guide\intro\typst-img")),
+            "guide/intro/typst-img"
+        );
+
+        // Placeholders carry indexes, there is no path to get mangled
+        let tag = placeholder(IMAGE_PLACEHOLDER, 7);
+        assert_eq!(placeholder_index(&tag, IMAGE_PLACEHOLDER), Some(7));
+        assert_eq!(placeholder_index(&tag, MARKDOWN_PLACEHOLDER), None);
+        assert_eq!(
+            replace_placeholders(&format!("<p>{tag}</p>"), IMAGE_PLACEHOLDER, |index| {
+                Some(format!("image {index}"))
+            }),
+            "<p>image 7</p>"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn image_urls_use_slashes() {
+        let book = TestBook::with_typst("image-urls", STUB_RENDER);
+        let chapter = [("guide/intro.md", RENDERED)];
+
+        let html = book.chapters("", &chapter).unwrap().remove(0);
+        assert!(html.contains(r#"src="typst-img/"#), "{html}");
+        let html = book
+            .chapters("out_dir = \"src/assets/rendered\"", &chapter)
+            .unwrap()
+            .remove(0);
+        assert!(
+            html.contains(r#"src="../assets/rendered/guide/typst-img/"#),
+            "{html}"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn out_dir_outside_the_sources_is_linked_from_the_output() {
+        let book = TestBook::with_typst("outside-out-dir", STUB_RENDER);
+        let chapter = [("guide/intro.md", RENDERED)];
+
+        let html = book
+            .chapters("out_dir = \"target/typst-cache\"", &chapter)
+            .unwrap()
+            .remove(0);
+        // From `book/guide/intro.html`
+        assert!(
+            html.contains(r#"src="../../target/typst-cache/guide/typst-img/"#),
+            "{html}"
+        );
+        assert!(!html.contains("data:"), "{html}");
+
+        // From `book/html/guide/intro.html`
+        let html = book
+            .chapters(
+                "out_dir = \"target/typst-cache\"\n[output.html]\n[output.markdown]",
+                &chapter,
+            )
+            .unwrap()
+            .remove(0);
+        assert!(
+            html.contains(r#"src="../../../target/typst-cache/guide/typst-img/"#),
+            "{html}"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unreadable_svgs_are_kept_as_they_are() {
+        let book = TestBook::with_typst(
+            "optimize-fallback",
+            &STUB_RENDER.replace(
+                "echo '<svg width=\"10pt\" height=\"10pt\"></svg>'",
+                "printf '\\377'",
+            ),
+        );
+
+        book.html("optimize_svg = true", RENDERED).unwrap();
+        let [image] = &book.images()[..] else {
+            panic!("{:?}", book.images())
+        };
+        let path = book.root.join("src/typst-img").join(image);
+        assert_eq!(fs::read(path).unwrap(), b"\xff");
+        assert!(
+            book.warnings()
+                .iter()
+                .any(|w| w.starts_with("Can't optimize") && w.contains("keeping it as is")),
+            "{:?}",
+            book.warnings()
+        );
+    }
+
+    /// Average time of `f` over `runs` runs
+    fn average_time<T>(runs: u32, mut f: impl FnMut() -> T) -> Duration {
+        let started = std::time::Instant::now();
+        for _ in 0..runs {
+            std::hint::black_box(f());
+        }
+        started.elapsed() / runs
+    }
+
+    /// Startup cost of the syntaxes, loading a set and highlighting the first snippet with it,
+    /// which links the syntaxes it needs: `minimal_syntaxes` against the default set, and the
+    /// embedded dump against building the set at runtime, as it was done before `build.rs` did.
+    /// `cargo test --release -- --ignored --nocapture syntax_set_startup`
+    #[test]
+    #[ignore]
+    fn syntax_set_startup() {
+        let theme = theme(DEFAULT_THEME).unwrap();
+        let first_snippet = |syntax_set: SyntaxSet| {
+            let options = HighlightOptions {
+                syntax_set: &syntax_set,
+                ..HighlightOptions::new(&theme)
+            };
+            highlight_typst("#let f(x) = $x^2$\n= Title\n", &options).unwrap()
+        };
+
+        let full = average_time(20, || {
+            first_snippet(from_binary(include_bytes!(concat!(
+                env!("OUT_DIR"),
+                "/syntaxes.packdump"
+            ))))
+        });
+        let minimal = average_time(20, || {
+            first_snippet(from_binary(include_bytes!(concat!(
+                env!("OUT_DIR"),
+                "/typst.packdump"
+            ))))
+        });
+        println!("default syntaxes: {full:?}, minimal_syntaxes: {minimal:?}");
+
+        let build = || {
+            let typst = fs::read_to_string(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/res/Typst.sublime-syntax"
+            ))
+            .unwrap();
+            let mut syntaxes = SyntaxSet::load_defaults_nonewlines().into_builder();
+            syntaxes.add(SyntaxDefinition::load_from_str(&typst, true, None).unwrap());
+            syntaxes.build()
+        };
+        // The dump has to highlight exactly like the set it is made of
+        assert_eq!(first_snippet(build()), first_snippet(SYNTAX.clone()));
+        println!(
+            "built at runtime: {:?}",
+            average_time(5, || first_snippet(build()))
+        );
+    }
+
+    /// Inline code 100 times longer than `max_inline_length` allows, left plain by default
+    /// and highlighted without the limit.
+    /// `cargo test --release -- --ignored --nocapture long_inline_span`
+    #[test]
+    #[ignore]
+    fn long_inline_span() {
+        let book = TestBook::new("long-inline-bench");
+        let content = format!("`{}`\n", "#f(x) + ".repeat(25_000));
+        let limited = average_time(5, || book.html("", &content).unwrap());
+        let unlimited = average_time(5, || {
+            book.html(
+                "[preprocessor.typst-highlight.limits]\nmax_inline_length = 1000000",
+                &content,
+            )
+            .unwrap()
+        });
+        println!("200000 characters: {limited:?} plain, {unlimited:?} highlighted");
+    }
+
+    #[test]
+    fn long_inline_code_is_left_plain() {
+        let book = TestBook::new("long-inline");
+        let long = "#f(x) < ".repeat(300);
+
+        let html = book.html("", &format!("`{long}` and `#f(x)`\n")).unwrap();
+        let spans = html.matches("<span").count();
+        assert!(html.contains(&escape_html(&long)), "{html}");
+        // Only the short one is highlighted
+        let html = book.html("", "`#f(x)`\n").unwrap();
+        assert_eq!(spans, html.matches("<span").count());
+        assert!(spans > 0, "{html}");
+    }
+
+    /// Highlighting of a synthetic book of 100 chapters with 10 blocks each on one thread
+    /// against all cores, see `HighlightJobs::run`.
+    /// `cargo test --release -- --ignored --nocapture parallel_highlighting`
+    #[test]
+    #[ignore]
+    fn parallel_highlighting() {
+        let settings = PreprocessSettings {
+            config: PreprocessConfig::default(),
+            book_theme: None,
+            style_classes: StyleClasses::default(),
+            src_dir: PathBuf::new(),
+            out_dir_url: None,
+            cache_dir: PathBuf::new(),
+            embed_assets: false,
+            font_dirs: vec![],
+            font_faces: vec![],
+            syntax_set: None,
+            extra_languages: BTreeSet::new(),
+            typst_version: String::new(),
+        };
+        let themes = Arc::new(Themes::Single(theme(DEFAULT_THEME).unwrap()));
+        let src = "#let f(x, y: 2) = { x + y } // Sum\n= Heading *bold* _emph_ $a^2$\n".repeat(10);
+        let jobs = || {
+            let mut jobs = HighlightJobs::default();
+            for chapter in 0..100 {
+                for _ in 0..10 {
+                    jobs.push(HighlightJob::new(
+                        src.clone(),
+                        &themes,
+                        &chapter.to_string(),
+                    ));
+                }
+            }
+            jobs
+        };
+
+        let one_thread = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap();
+        assert_eq!(
+            one_thread.install(|| jobs().run(&settings)),
+            jobs().run(&settings)
+        );
+        let sequential = average_time(3, || one_thread.install(|| jobs().run(&settings)));
+        let parallel = average_time(3, || jobs().run(&settings));
+        println!(
+            "1000 blocks: {sequential:?} sequential, {parallel:?} on {} threads",
+            rayon::current_num_threads()
+        );
+    }
+
+    #[test]
+    fn unknown_settings_are_rejected() {
+        let book = TestBook::new("unknown-settings");
+        let error = book.error("themee = \"GitHub\"", "");
+        assert!(error.contains("`themee`"), "{error}");
+
+        // Keys mdBook reads itself are fine
+        book.html("before = [\"links\"]", "").unwrap();
+    }
+
+    #[test]
+    fn code_in_lists_and_blockquotes_stays_there() {
+        let book = TestBook::new("nested-blocks");
+        let html = book
+            .html(
+                "",
+                "- item\n\n  ```typ\n  #set text(red)\n  ```\n\n> quote\n>\n> ```typ\n> = Title\n> ```\n",
+            )
+            .unwrap();
+
+        let position = |pattern: &str| html.find(pattern).unwrap();
+        let list = position("<li>")..position("</li>");
+        let quote = position("<blockquote>")..position("</blockquote>");
+        assert!(list.contains(&position("#set text")), "{html}");
+        assert!(quote.contains(&position("Title")), "{html}");
+    }
+
+    #[test]
+    fn inline_code_in_links_keeps_the_link() {
+        let book = TestBook::new("link-code");
+        let html = book
+            .html("", "See [typst `$x$`](http://example.com) here.\n")
+            .unwrap();
+
+        let link = html.find(r#"<a href="http://example.com">"#).unwrap();
+        let code = html.find("<code").unwrap();
+        let end = html.find("</a>").unwrap();
+        assert!(link < code && code < end, "{html}");
+        assert!(html.ends_with(" here.</p>\n"), "{html}");
+    }
+
+    #[test]
+    fn highlight_classes_replace_inline_styles() {
+        let book = TestBook::new("classes");
+        let html = book.html("highlight_classes = true", RENDERED).unwrap();
+        assert!(html.contains("<span class=\"typst-"), "{html}");
+        assert!(!html.contains("<span style="), "{html}");
+
+        // The stylesheet has the theme's rules inside the class of the theme
+        let (style, code) = html.split_once("</style>").unwrap();
+        let start = code.find("typst-theme-").unwrap();
+        let theme_class = &code[start..start + "typst-theme-".len() + 8];
+        assert!(
+            code.contains(&format!("nohighlight hljs {theme_class}")),
+            "{code}"
+        );
+        assert!(
+            style.contains(&format!(".{theme_class} {{ color: var(--fg); }}")),
+            "{style}"
+        );
+        assert!(
+            style.contains(&format!(".{theme_class} .typst-comment")),
+            "{style}"
+        );
+    }
+
+    #[test]
+    fn highlight_classes_size_compared_to_inline_styles() {
+        let book = TestBook::new("classes-size");
+        let lines = "#let f(x, y: 2) = { x + y } // Sum\n= Heading *bold* _emph_ $a^2$\n";
+        let listing = |n| format!("```typ\n{}```\n", lines.repeat(n));
+        let styled = book.html("", &listing(200)).unwrap();
+        let classed = book
+            .html("highlight_classes = true", &listing(200))
+            .unwrap();
+
+        // The stylesheet is the theme's, however long the listing is
+        let short = book.html("highlight_classes = true", &listing(1)).unwrap();
+        let stylesheet = |html: &str| html.split_once("</style>").unwrap().0.to_owned();
+        assert_eq!(stylesheet(&classed), stylesheet(&short));
+
+        // Scopes of Typst nest deeply, so the spans are longer than with inline styles
+        let ratio = classed.len() as f64 / styled.len() as f64;
+        assert!(
+            ratio < 4.0,
+            "{} bytes with classes, {} with styles",
+            classed.len(),
+            styled.len()
+        );
+    }
+
+    #[test]
+    fn missing_typst_is_reported() {
+        let book = TestBook::new("missing-typst");
+        let error = book.error(
+            "render = true\ntypst_path = \"/nonexistent/typst\"",
+            RENDERED,
+        );
+        assert!(error.contains("Typst can't be run"), "{error}");
+
+        // Highlighting alone doesn't need Typst
+        book.html("typst_path = \"/nonexistent/typst\"", RENDERED)
+            .unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn typst_errors_point_to_the_chapter() {
+        let book = TestBook::with_typst(
+            "typst-error",
+            "echo 'error: unknown variable: x' >&2\necho '  ┌─ <stdin>:3:2' >&2\nexit 1",
+        );
+        let error = book.error("strict = true", RENDERED);
+        assert!(error.contains("unknown variable: x"), "{error}");
+        assert!(error.contains("┌─ test.md:2:2"), "{error}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn silent_typst_failures_are_reported() {
+        let mut book = TestBook::with_typst("silent-typst", "exit 1");
+        let error = book.error("strict = true", RENDERED);
+        assert!(
+            error.contains("Typst failed with exit status: 1"),
+            "{error}"
+        );
+
+        // Success without an image fails only strict builds
+        book.stub_typst("exit 0");
+        book.html("", RENDERED).unwrap();
+        let error = book.error("strict = true", RENDERED);
+        assert!(error.contains("Typst produced no"), "{error}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hanging_typst_times_out() {
+        let book = TestBook::with_typst("typst-timeout", "sleep 10");
+        let started = std::time::Instant::now();
+        let error = book.error(
+            "strict = true\n[preprocessor.typst-highlight.limits]\nrender_timeout_secs = 1",
+            RENDERED,
+        );
+        assert!(error.contains("timed out after 1 s"), "{error}");
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn max_parallel_limits_typst_processes() {
+        let mut book = TestBook::new("typst-parallel");
+        let lock = book.root.join("lock");
+        book.stub_typst(&format!(
+                "if [ -e {lock:?} ]; then echo 'error: two at once' >&2; exit 1; fi\ntouch {lock:?}\nsleep 0.2\nrm {lock:?}\n{STUB_RENDER}"
+        ));
+        let blocks = ["= One", "= Two", "= Three"]
+            .map(|src| format!("```typ\n{src}\n```\n"))
+            .join("\n");
+        book.html(
+            "strict = true\n[preprocessor.typst-highlight.limits]\nmax_parallel = 1",
+            &blocks,
+        )
+        .unwrap();
+        assert_eq!(book.images().len(), 3);
+
+        let error = book.error(
+            "[preprocessor.typst-highlight.limits]\nmax_parallel = 0",
+            "",
+        );
+        assert!(
+            error.contains("max_parallel must be greater than zero"),
+            "{error}"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn typst_warnings_dont_fail_strict_builds() {
+        let book = TestBook::with_typst(
+            "typst-warning",
+            &format!("echo 'warning: unknown font family: foo' >&2\n{STUB_RENDER}"),
+        );
+        let html = book
+            .html("strict = true\nshow_errors_inline = true", RENDERED)
+            .unwrap();
+        assert!(!html.contains("typst-error"), "{html}");
+        assert_eq!(book.images().len(), 1);
+        let warnings = book.warnings();
+        assert!(
+            warnings
+                .iter()
+                .any(|warning| warning.contains("unknown font family: foo")),
+            "{warnings:?}"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn extra_args_are_passed_to_typst() {
+        let mut book = TestBook::new("typst-args");
+        let args = book.root.join("args");
+        book.stub_typst(&format!("echo \"$@\" > {args:?}\n{STUB_RENDER}"));
+        book.html("extra_args = [\"--pdf-standard=a-2b\"]", RENDERED)
+            .unwrap();
+
+        let args = fs::read_to_string(args).unwrap();
+        assert!(args.starts_with("c - --root"), "{args}");
+        assert!(args.contains(" --pdf-standard=a-2b "), "{args}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn features_are_passed_to_typst() {
+        let mut book = TestBook::new("typst-features");
+        let args = book.root.join("args");
+        book.stub_typst(&format!("echo \"$@\" > {args:?}\n{STUB_RENDER}"));
+        book.html(
+            "features = [\"html\"]\nextra_args = [\"--pdf-standard=a-2b\"]",
+            RENDERED,
+        )
+        .unwrap();
+
+        // Features come before the extra arguments, which may override them
+        let args = fs::read_to_string(args).unwrap();
+        assert!(
+            args.contains(" --features html --pdf-standard=a-2b "),
+            "{args}"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn failed_package_downloads_are_retried() {
+        let mut book = TestBook::new("typst-retry");
+        let failed = book.root.join("failed");
+        book.stub_typst(&format!(
+            "if [ ! -e {failed:?} ]; then touch {failed:?}; echo 'error: failed to download package' >&2; exit 1; fi\n{STUB_RENDER}"
+        ));
+        let settings = "strict = true\nforce_render = true";
+        let error = book.error(settings, RENDERED);
+        assert!(error.contains("failed to download package"), "{error}");
+        assert!(book.images().is_empty());
+
+        fs::remove_file(&failed).unwrap();
+        book.html(&format!("{settings}\nrender_retries = 1"), RENDERED)
+            .unwrap();
+        assert_eq!(book.images().len(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn compile_errors_are_not_retried() {
+        let mut book = TestBook::new("typst-no-retry");
+        let runs = book.root.join("runs");
+        book.stub_typst(&format!(
+            "echo run >> {runs:?}\necho 'error: unknown variable: network' >&2\nexit 1"
+        ));
+        let error = book.error("strict = true\nrender_retries = 2", RENDERED);
+        assert!(error.contains("unknown variable: network"), "{error}");
+        assert_eq!(fs::read_to_string(&runs).unwrap(), "run\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn thumbnails_link_to_the_full_image() {
+        let book = TestBook::with_typst("thumbnail", STUB_RENDER);
+        let html = book
+            .html("thumbnail = true\nthumbnail_size = 5", RENDERED)
+            .unwrap();
+
+        let images = book.images();
+        let [thumb, full] = images.as_slice() else {
+            panic!("{images:?}");
+        };
+        assert_eq!(thumb, &full.replace(".svg", "-thumb.svg"));
+        assert!(
+            html.contains(&format!(
+                r#"<a href="typst-img/{full}"><img align="middle" src="typst-img/{thumb}""#
+            )),
+            "{html}"
+        );
+        let svg = fs::read_to_string(book.root.join("src/typst-img").join(thumb)).unwrap();
+        assert!(svg.contains(r#"<svg width="5pt" height="5pt">"#), "{svg}");
+
+        let book = TestBook::with_typst("png-thumbnail", STUB_RENDER);
+        let html = book
+            .html("thumbnail = true\nformat = \"png\"", RENDERED)
+            .unwrap();
+        assert!(!html.contains("<a href"), "{html}");
+        assert!(book
+            .warnings()
+            .iter()
+            .any(|warning| warning.starts_with("Ignoring thumbnail = true")),);
+    }
+
+    #[test]
+    fn disable_foreground_hack_keeps_the_theme_foreground() {
+        let book = TestBook::new("foreground-hack");
+        let code = "```typ\nplain text\n```\n";
+
+        let html = book.html("", code).unwrap();
+        assert!(html.contains("color:var(--fg);"), "{html}");
+        assert!(!html.contains("#1bdf3363"), "{html}");
+
+        // Solarized (dark)'s own foreground
+        let html = book.html("disable_foreground_hack = true", code).unwrap();
+        assert!(html.contains("color:#839496;"), "{html}");
+        assert!(!html.contains("var(--fg)"), "{html}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn paragraph_settings_go_into_the_preamble() {
+        let book = TestBook::with_typst("paragraphs", STUB_RENDER);
+        let settings =
+            "render_par_justify = true\nrender_par_leading = \"0.8em\"\nforce_render = true";
+        let par = |info: &str| {
+            book.html(settings, &format!("```{info}\n= Title\n```\n"))
+                .unwrap();
+            book.typst_input()
+                .lines()
+                .find(|line| line.starts_with("#set par"))
+                .map(str::to_owned)
+        };
+
+        assert_eq!(
+            par("typ").as_deref(),
+            Some("#set par(justify: true, leading: 0.8em)")
+        );
+        assert_eq!(
+            par("typ,nojustify").as_deref(),
+            Some("#set par(justify: false, leading: 0.8em)")
+        );
+        assert_eq!(par("typ,nopreamble"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn golden_images_are_compared() {
+        let book = TestBook::with_typst("golden", STUB_RENDER);
+        let golden = book.root.join("golden/test/block-1-1.svg");
+        book.html("golden = \"golden\"\ngolden_update = true", RENDERED)
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(&golden).unwrap(),
+            "<svg width=\"10pt\" height=\"10pt\"></svg>\n"
+        );
+        book.html("golden = \"golden\"", RENDERED).unwrap();
+
+        // One of the two lines differs
+        fs::write(&golden, "<svg width=\"20pt\" height=\"10pt\"></svg>\n").unwrap();
+        let error = book.error("golden = \"golden\"", RENDERED);
+        assert!(error.contains("differ from golden ones"), "{error}");
+        assert!(error.contains("block-1-1.svg (50.00% of lines)"), "{error}");
+        book.html("golden = \"golden\"\ngolden_threshold = 0.5", RENDERED)
+            .unwrap();
+
+        let error = book.error("golden = \"golden\"\nformat = \"png\"", RENDERED);
+        assert!(
+            error.contains("it can't be used with format = \"png\""),
+            "{error}"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn normalization_forms_share_a_cache_name() {
+        let nfc = "```typ\nCaf\u{e9}\n```\n";
+        let nfd = "```typ\nCafe\u{301}\n```\n";
+        let book = TestBook::with_typst("normalization", STUB_RENDER);
+
+        let names = book
+            .chapters("", &[("nfd.md", nfd), ("nfc.md", nfc)])
+            .unwrap();
+        let name = |html: &str| html.split("typst-img/").nth(1).unwrap()[..64].to_owned();
+        assert_eq!(name(&names[0]), name(&names[1]));
+        assert_eq!(book.images().len(), 1);
+        // Typst gets the source as written
+        assert!(book.typst_input().contains("Cafe\u{301}\n"));
+
+        let book = TestBook::with_typst("no-normalization", STUB_RENDER);
+        let names = book
+            .chapters(
+                "unicode_normalization = \"none\"",
+                &[("nfc.md", nfc), ("nfd.md", nfd)],
+            )
+            .unwrap();
+        assert_ne!(name(&names[0]), name(&names[1]));
+
+        let book = TestBook::with_typst("source-normalization", STUB_RENDER);
+        book.html("unicode_normalization = \"source\"", nfd)
+            .unwrap();
+        assert!(book.typst_input().contains("Caf\u{e9}\n"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn markdown_figures_are_markdown_images() {
+        let book = TestBook::with_typst("markdown-figures", STUB_RENDER);
+        let markdown = book.markdown("markdown_figures = true", RENDERED).unwrap();
+
+        let [image] = book.images().try_into().unwrap();
+        assert!(
+            markdown.contains(&format!("![Rendered image](typst-img/{image})")),
+            "{markdown}"
+        );
+        assert!(!markdown.contains("<img"), "{markdown}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn source_download_links_the_compiled_source() {
+        let book = TestBook::with_typst("source-download", STUB_RENDER);
+        let html = book.html("source_download = true", RENDERED).unwrap();
+
+        let [image] = book.images().try_into().unwrap();
+        let source = image.replace("-1.svg", ".typ");
+        assert!(
+            html.contains(&format!(
+                r#"<a href="typst-src/{source}" download>Download .typ</a>"#
+            )),
+            "{html}"
+        );
+        let source = fs::read_to_string(book.root.join("src/typst-src").join(source)).unwrap();
+        assert!(source.starts_with(PREAMBLE), "{source}");
+        assert!(source.ends_with("= Title\n"), "{source}");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn read_only_sources_fall_back_to_a_temporary_cache() {
+        let book = TestBook::with_typst("read-only", STUB_RENDER);
+        // Not even root can create files there
+        let html = book.html("[book]\nsrc = \"/proc/self\"", RENDERED).unwrap();
+        assert!(
+            html.contains(r#"src="data:image/svg+xml;base64,"#),
+            "{html}"
+        );
+
+        let cache_dir = std::env::temp_dir().join("mdbook-typst-highlight").join(
+            &sha256_hash(&fs::canonicalize(&book.root).unwrap().to_string_lossy())
+                [..SHORT_HASH_LEN],
+        );
+        let images = fs::read_dir(cache_dir.join("typst-img"))
+            .unwrap()
+            .flatten()
+            .filter(|entry| entry.path().extension() == Some(OsStr::new("svg")))
+            .count();
+        assert_eq!(images, 1);
+        fs::remove_dir_all(cache_dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn nopreamble_blocks_get_their_own_names() {
+        let book = TestBook::with_typst("nopreamble-names", STUB_RENDER);
+        let html = book
+            .html(
+                "",
+                "```typ\n= Title\n```\n\n```typ,nopreamble\n= Title\n```\n",
+            )
+            .unwrap();
+
+        let images = book.images();
+        assert_eq!(images.len(), 2, "{images:?}");
+        for image in images {
+            assert!(html.contains(&format!("typst-img/{image}")), "{html}");
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn typst_upgrades_render_blocks_again() {
+        let book = TestBook::with_typst("typst-upgrade", STUB_RENDER);
+        let old = book.html("", RENDERED).unwrap();
+        assert!(!book.typst_input().is_empty());
+        book.html("", RENDERED).unwrap();
+        assert!(book.typst_input().is_empty(), "a cached block is rendered");
+
+        book.set_typst_version("typst 0.14.0 (b790c2b3)");
+        let new = book.html("prune_cache = true", RENDERED).unwrap();
+        assert!(!book.typst_input().is_empty());
+        assert_ne!(old, new);
+        assert_eq!(book.images().len(), 1, "the old image isn't pruned");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn typst_path_is_relative_to_the_book() {
+        let mut book = TestBook::with_typst("typst-path", STUB_RENDER);
+        // Given by the test, not by the fixture
+        book.typst = None;
+        let html = book
+            .html("render = true\ntypst_path = \"./typst\"", RENDERED)
+            .unwrap();
+
+        assert_eq!(book.typst_input(), format!("{PREAMBLE}\n= Title\n"));
+        let [image] = book.images().try_into().unwrap();
+        assert!(html.contains(&format!("typst-img/{image}")), "{html}");
+    }
+
+    #[test]
+    fn hl_emphasizes_the_given_lines() {
+        let book = TestBook::new("hl");
+        let html = book
+            .html(
+                "",
+                "```typ,hl=2,4\n#let a = 1\n#let b = 2\n#let c = 3\n```\n",
+            )
+            .unwrap();
+
+        let emphasized = html
+            .split(r#"<span class="highlighted-line""#)
+            .skip(1)
+            .collect::<Vec<_>>();
+        let [line] = emphasized.as_slice() else {
+            panic!("{html}");
+        };
+        let line = &line[..line.find('\n').unwrap()];
+        let line = format!("<span{line}");
+        assert_eq!(text(&line), "#let b = 2");
+        // The line break is after the wrapper
+        assert_eq!(
+            line.matches("<span").count(),
+            line.matches("</span>").count()
+        );
+        assert!(book
+            .warnings()
+            .contains(&"Ignoring invalid line range `4` in hl".to_owned()));
+    }
+
+    /// Stub script that fails like Typst if `utils.typ` isn't in the root
+    #[cfg(unix)]
+    const STUB_IMPORT: &str = r#"root=$(echo "$@" | sed 's/.*--root \([^ ]*\).*/\1/')
+[ -f "$root/utils.typ" ] || { echo "error: file not found (searched at $root/utils.typ)" >&2; exit 1; }"#;
+
+    #[cfg(unix)]
+    #[test]
+    fn blocks_import_files_next_to_their_chapter() {
+        let book = TestBook::with_typst("sibling-import", &format!("{STUB_IMPORT}\n{STUB_RENDER}"));
+        book.write("src/guide/utils.typ", "#let x = 1\n");
+        let block = "```typ\n#import \"utils.typ\": x\n#x\n```\n";
+
+        book.chapters("strict = true", &[("guide/intro.md", block)])
+            .unwrap();
+        assert_eq!(book.images().len(), 1);
+
+        let error = book
+            .chapters("strict = true", &[("other/intro.md", block)])
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("file not found"), "{error}");
+    }
+
+    #[test]
+    fn snippets_that_cant_be_highlighted_are_shown_plain() {
+        let book = TestBook::new("highlight-fallback");
+        // Embeds a syntax that isn't there, so parsing the first line fails
+        book.write(
+            "broken.sublime-syntax",
+            "%YAML 1.2\n---\nname: Broken\nfile_extensions: [broken]\nscope: source.broken\n\
+             contexts:\n  main:\n    - match: '<'\n      push: scope:source.missing\n",
+        );
+
+        let html = book
+            .html(
+                "extra_syntaxes = [\"broken.sublime-syntax\"]",
+                "```broken\na < b & c\n```\n",
+            )
+            .unwrap();
+        assert!(html.contains("a &lt; b &amp; c"), "{html}");
+        assert!(
+            book.warnings()
+                .iter()
+                .any(|w| w
+                    .starts_with("Can't highlight a snippet in chapter \"highlight-fallback\"")),
+            "{:?}",
+            book.warnings()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hidesrc_shows_only_the_image() {
+        let mut book = TestBook::with_typst("hidesrc", STUB_RENDER);
+        let block = "```typ-hidesrc\n= Hidden\n```\n";
+
+        let html = book.html("", block).unwrap();
+        assert!(html.contains("<img"), "{html}");
+        assert!(
+            !html.contains("<pre") && !text(&html).contains("Hidden"),
+            "{html}"
+        );
+
+        // Nothing to show instead of the code
+        let html = TestBook::new("hidesrc-unrendered").html("", block).unwrap();
+        assert!(text(&html).contains("= Hidden"), "{html}");
+
+        let error = book.error("strict = true", "```typ-hidesrc-norender\n= Hidden\n```\n");
+        assert!(
+            error.contains("can't be both hidesrc and norender"),
+            "{error}"
+        );
+
+        // Errors of hidden blocks are still reported
+        book.stub_typst("echo 'error: unknown variable' >&2; exit 1");
+        let error = book.error("strict = true", "```typ-hidesrc\n#broken\n```\n");
+        assert!(error.contains("unknown variable"), "{error}");
+    }
+
+    #[test]
+    fn highlighting_theme_follows_the_book_theme() {
+        let book = TestBook::new("book-theme");
+        // Backgrounds of the Solarized themes
+        let dark = "#002b36";
+        let light = "#fdf6e3";
+        let settings = "include_background = true";
+
+        let html = book.html(settings, RENDERED).unwrap();
+        assert!(html.contains(dark) && !html.contains(light), "{html}");
+
+        let html = book
+            .html(
+                &format!("{settings}\n[output.html]\ndefault-theme = \"rust\""),
+                RENDERED,
+            )
+            .unwrap();
+        assert!(html.contains(light), "{html}");
+
+        let html = book
+            .html(
+                &format!("{settings}\ntheme_map = {{ rust = \"InspiredGitHub\" }}\n[output.html]\ndefault-theme = \"rust\""),
+                RENDERED,
+            )
+            .unwrap();
+        assert!(!html.contains(light) && !html.contains(dark), "{html}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn bg_sets_the_backing_of_one_image() {
+        let book = TestBook::with_typst("block-bg", STUB_RENDER);
+
+        let html = book.html("", "```typ,bg=#1e1e1e\n= Dark\n```\n").unwrap();
+        let img = &html[html.find("<img").unwrap()..];
+        assert!(img.contains("background: #1e1e1e;"), "{html}");
+        assert!(!img.contains("white"), "{html}");
+        // The padded box keeps its own background
+        assert!(html.contains("background: var(--quote-bg);"), "{html}");
+
+        let html = book
+            .html("", "```typ,bg=red;color:red\n= Dark\n```\n")
+            .unwrap();
+        assert!(html.contains("background: white;"), "{html}");
+        assert!(
+            book.warnings()
+                .iter()
+                .any(|w| w.starts_with("Ignoring bg=red;color:red")),
+            "{:?}",
+            book.warnings()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn bare_blocks_keep_their_classes_when_the_preamble_fails() {
+        let book = TestBook::with_typst("bare-preamble", STUB_RENDER);
+        let block = "```typ,class=wide,preamble=missing.typ\n= Title\n```\n";
+
+        let html = book.html("bare_output = true", block).unwrap();
+        assert!(!html.contains("<pre"), "{html}");
+        assert!(html.starts_with(r#"<div class="wide""#), "{html}");
+        assert!(book.images().is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn preamble_enabled_false_skips_every_preamble() {
+        let book = TestBook::with_typst("preamble-enabled", STUB_RENDER);
+        book.write("src/extra.typ", "#let extra = 1");
+        let block = "```typ,preamble=extra.typ\n= Title\n```\n";
+
+        book.html("", block).unwrap();
+        let input = book.typst_input();
+        assert!(
+            input.starts_with(PREAMBLE) && input.contains("#let extra = 1"),
+            "{input}"
+        );
+
+        book.html(
+            "preamble = \"#let custom = 1\"\npreamble_enabled = false",
+            block,
+        )
+        .unwrap();
+        assert_eq!(book.typst_input(), "= Title\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn page_settings_make_the_page_line() {
+        let book = TestBook::with_typst("page-settings", STUB_RENDER);
+        let page_line = |settings: &str| {
+            book.html(settings, RENDERED).unwrap();
+            let input = book.typst_input();
+            input.lines().next().unwrap().to_owned()
+        };
+
+        assert_eq!(page_line(""), PREAMBLE.trim_end());
+        assert_eq!(
+            page_line("page_width = \"15cm\""),
+            "#set page(height: auto, width: 15cm, margin: 0.5cm)"
+        );
+        assert_eq!(
+            page_line("page_height = \"10cm\"\nmargin = \"0pt\""),
+            "#set page(height: 10cm, width: 400pt, margin: 0pt)"
+        );
+        // Every page line gets its own image
+        assert_eq!(book.images().len(), 3);
+
+        assert_eq!(
+            page_line("page_width = \"wide\"\nmargin = \"1em\""),
+            "#set page(height: auto, width: 400pt, margin: 1em)"
+        );
+        assert!(
+            book.warnings()
+                .contains(&"Ignoring page_width = \"wide\", it isn't a Typst length".to_owned()),
+            "{:?}",
+            book.warnings()
+        );
+        assert_eq!(
+            page_line("preamble = \"#let raw = 1\"\npage_width = \"15cm\""),
+            "#let raw = 1"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn shared_cache_renders_a_block_once_for_the_book() {
+        let book = TestBook::with_typst("shared-cache", STUB_RENDER);
+        let chapters = [("intro.md", RENDERED), ("guide/usage.md", RENDERED)];
+
+        let html = book.chapters("shared_cache = true", &chapters).unwrap();
+        let [image] = &book.images()[..] else {
+            panic!("{:?}", book.images())
+        };
+        assert!(
+            html[0].contains(&format!(r#"src="typst-img/{image}""#)),
+            "{}",
+            html[0]
+        );
+        assert!(
+            html[1].contains(&format!(r#"src="../typst-img/{image}""#)),
+            "{}",
+            html[1]
+        );
+        assert_eq!(book.typst_input().matches("= Title").count(), 1);
+
+        // Without it, every directory has its own copy
+        fs::remove_dir_all(book.root.join("src/typst-img")).unwrap();
+        book.chapters("", &chapters).unwrap();
+        assert_eq!(book.images().len(), 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn images_get_their_size_and_lazy_loading() {
+        let book = TestBook::with_typst(
+            "image-size",
+            &STUB_RENDER.replace(
+                "echo '<svg width=\"10pt\" height=\"10pt\"></svg>'",
+                "cat \"$0-fixture\"",
+            ),
+        );
+        // The head of an SVG of Typst 0.13
+        book.write(
+            "typst-fixture",
+            concat!(
+                r#"<svg class="typst-doc" viewBox="0 0 283.46 36.13" width="283.46pt" height="36.13pt" "#,
+                r#"xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink">"#,
+                "\n    <path class=\"typst-shape\" fill=\"#ffffff\" d=\"M 0 0 v 36.13 h 283.46 v -36.13 Z \"/>\n</svg>\n"
+            ),
+        );
+
+        let html = book.html("", RENDERED).unwrap();
+        // 1pt is 4/3px
+        assert!(
+            html.contains(r#"loading="lazy" width="378" height="48""#),
+            "{html}"
+        );
+
+        let html = book.html("lazy_loading = false", RENDERED).unwrap();
+        assert!(
+            !html.contains("loading=") && !html.contains("width=\""),
+            "{html}"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn blocks_without_images_are_reported() {
+        // Typst succeeds, but writes nothing
+        let book = TestBook::with_typst("no-images", "exit 0");
+
+        book.html("", RENDERED).unwrap();
+        assert!(
+            book.warnings().contains(
+                &"A block in chapter \"no-images\" produced no images:\n= Title".to_owned()
+            ),
+            "{:?}",
+            book.warnings()
+        );
+
+        let error = book.error("strict = true", RENDERED);
+        assert!(error.contains("Typst produced no"), "{error}");
+    }
+
+    #[test]
+    fn blocks_can_have_their_own_theme() {
+        let book = TestBook::new("block-theme");
+        let colors = |html: &str| {
+            html.split("color:")
+                .skip(1)
+                .map(|rest| rest[..rest.find(';').unwrap()].to_owned())
+                .collect::<Vec<_>>()
+        };
+        let block = |info: &str| format!("```{info}\n#let x = \"text\"\n```\n");
+
+        let solarized = colors(&book.html("", &block("typ")).unwrap());
+        let github = colors(&book.html("", &block("typ,theme=InspiredGitHub")).unwrap());
+        let light = colors(
+            &book
+                .html("", &block("typ,theme=Solarized (light)"))
+                .unwrap(),
+        );
+        assert!(!solarized.is_empty());
+        assert_ne!(solarized, github);
+        assert_ne!(github, light);
+
+        let unknown = colors(&book.html("", &block("typ,theme=Nope")).unwrap());
+        assert_eq!(unknown, solarized);
+        assert!(
+            book.warnings().iter().any(|w| w.contains("Nope")),
+            "{:?}",
+            book.warnings()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn inline_svgs_on_one_page_keep_their_own_ids() {
+        let book = TestBook::with_typst(
+            "inline-svg-ids",
+            &STUB_RENDER.replace(
+                "echo '<svg width=\"10pt\" height=\"10pt\"></svg>'",
+                r##"echo '<svg width="10pt" height="10pt"><defs><symbol id="g1"><path d="M 0 0"/></symbol><clipPath id="c1"/></defs><use xlink:href="#g1" clip-path="url(#c1)"/></svg>'"##,
+            ),
+        );
+
+        let html = book
+            .html(
+                "inline_svg = true",
+                "```typ\n= One\n```\n\n```typ\n= Two\n```\n",
+            )
+            .unwrap();
+        let svgs = html.split("<svg").skip(1).collect::<Vec<_>>();
+        assert_eq!(svgs.len(), 2, "{html}");
+        let ids = svgs
+            .iter()
+            .map(|svg| {
+                let start = svg.find("<symbol id=\"").unwrap() + "<symbol id=\"".len();
+                let id = &svg[start..start + svg[start..].find('"').unwrap()];
+                // References stay within their own image
+                assert!(svg.contains(&format!("xlink:href=\"#{id}\"")), "{svg}");
+                assert!(id.ends_with("g1"), "{id}");
+                let clip = id.strip_suffix("g1").unwrap().to_owned() + "c1";
+                assert!(svg.contains(&format!("url(#{clip})")), "{svg}");
+                id.to_owned()
+            })
+            .collect::<Vec<_>>();
+        assert_ne!(ids[0], ids[1]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn line_endings_and_trailing_lines_dont_change_blocks() {
+        let book = TestBook::with_typst("line-endings", STUB_RENDER);
+
+        let lf = book.html("", "```typ\n= Title\n#x\n```\n").unwrap();
+        let images = book.images();
+        assert_eq!(book.typst_input().lines().last(), Some("#x"));
+        let crlf = book
+            .html("", "```typ\r\n= Title\r\n#x\r\n\r\n  \r\n```\r\n")
+            .unwrap();
+        assert_eq!(lf, crlf);
+        assert_eq!(book.images(), images);
+        // Nothing was rendered again
+        assert_eq!(book.typst_input(), "");
+
+        let kept = book
+            .html("trim_trailing = false", "```typ\n= Title\n#x\n\n\n```\n")
+            .unwrap();
+        assert_ne!(kept, lf);
+        assert!(text(&kept).contains("#x\n\n"), "{kept}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn blocks_are_compiled_to_every_format() {
+        let book = TestBook::with_typst("formats", STUB_RENDER);
+        let settings = "formats = [\"svg\", \"pdf\"]";
+
+        let html = book.html(settings, RENDERED).unwrap();
+        let [svg, pdf] = &book.images()[..] else {
+            panic!("{:?}", book.images())
+        };
+        let name = svg.strip_suffix("-1.svg").unwrap();
+        assert_eq!(pdf, &format!("{name}.pdf"));
+        assert!(
+            html.contains(&format!(r#"<img align="middle" src="typst-img/{svg}""#)),
+            "{html}"
+        );
+        assert!(
+            html.contains(&format!(
+                r#"<a href="typst-img/{pdf}" download>Download PDF</a>"#
+            )),
+            "{html}"
+        );
+        assert_eq!(book.typst_input().matches("= Title").count(), 2);
+
+        // Cached formats aren't compiled again, missing ones are
+        fs::remove_file(book.root.join("src/typst-img").join(pdf)).unwrap();
+        book.html(settings, RENDERED).unwrap();
+        assert_eq!(book.typst_input().matches("= Title").count(), 1);
+        assert_eq!(book.images().len(), 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn force_render_compiles_cached_blocks_again() {
+        let book = TestBook::with_typst("force-render", STUB_RENDER);
+
+        book.html("", RENDERED).unwrap();
+        let images = book.images();
+        assert_eq!(book.typst_input().matches("= Title").count(), 1);
+        book.html("", RENDERED).unwrap();
+        assert_eq!(book.typst_input(), "");
+
+        book.html("force_render = true", RENDERED).unwrap();
+        assert_eq!(book.typst_input().matches("= Title").count(), 1);
+        // The cached images are overwritten
+        assert_eq!(book.images(), images);
+
+        assert!(env_flag(Some("1".into())) && env_flag(Some("yes".into())));
+        assert!(!env_flag(Some("0".into())) && !env_flag(Some("".into())) && !env_flag(None));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn file_labels_are_escaped_and_dont_change_rendering() {
+        let book = TestBook::with_typst("file-label", STUB_RENDER);
+
+        let html = book
+            .html(
+                "copy_button = true",
+                "```typ,file=<b>\"a&b\".typ\n= Title\n```\n",
+            )
+            .unwrap();
+        let label = r#"background: var(--quote-bg);">&lt;b&gt;&quot;a&amp;b&quot;.typ</div>"#;
+        let label_at = html.find(label).unwrap_or_else(|| panic!("{html}"));
+        assert!(label_at < html.find("<pre").unwrap(), "{html}");
+        assert!(!html.contains("<b>"), "{html}");
+        assert!(html.contains("typst-copy"), "{html}");
+        let images = book.images();
+
+        // The same block without a label is the same image
+        book.html("", RENDERED).unwrap();
+        assert_eq!(book.images(), images);
+        assert_eq!(book.typst_input().matches("= Title").count(), 1);
+    }
+
+    #[test]
+    fn wrap_lines_styles_only_blocks() {
+        let book = TestBook::new("wrap-lines");
+        let content = format!("```typ\n{}\n= Title\n```\n\n`#x`\n", "#x ".repeat(100));
+        let wrap = "white-space: pre-wrap; word-break: break-word";
+
+        let html = book.html("", &content).unwrap();
+        assert!(!html.contains(wrap), "{html}");
+
+        let html = book
+            .html("wrap_lines = true\nline_numbers = true", &content)
+            .unwrap();
+        assert_eq!(html.matches(wrap).count(), 1, "{html}");
+        assert!(
+            html.contains(&format!(r#"<pre style="margin: 0; {wrap}">"#)),
+            "{html}"
+        );
+        // Numbers are given to source lines
+        assert_eq!(html.matches("typst-lineno").count(), 2, "{html}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn render_include_and_exclude_pick_chapters() {
+        let book = TestBook::with_typst("render-globs", STUB_RENDER);
+        let paths = [
+            "intro.md",
+            "guide/usage.md",
+            "guide/drafts/next.md",
+            "guide/deep/more.md",
+        ];
+        let blocks = paths.map(|path| format!("```typ\n= {path}\n```\n"));
+        let chapters = paths
+            .iter()
+            .zip(&blocks)
+            .map(|(path, block)| (*path, block.as_str()))
+            .collect::<Vec<_>>();
+
+        let html = book
+            .chapters(
+                "render_include = [\"guide/**\"]\nrender_exclude = [\"guide/drafts/*.md\"]",
+                &chapters,
+            )
+            .unwrap();
+        let input = book.typst_input();
+        let rendered = paths.map(|path| input.contains(&format!("= {path}")));
+        assert_eq!(rendered, [false, true, false, true], "{input}");
+        for (html, rendered) in html.iter().zip(rendered) {
+            assert!(html.contains("<pre"), "{html}");
+            assert_eq!(html.contains("<img"), rendered, "{html}");
+        }
+    }
+
+    #[test]
+    fn inline_wrapper_chooses_the_element() {
+        let book = TestBook::new("inline-wrapper");
+        let content = "Inline `typ:#x` and `plain`\n";
+        let inline = |wrapper: &str| {
+            let html = book
+                .html(
+                    &format!("inline_prefix = \"typ:\"\ninline_wrapper = \"{wrapper}\""),
+                    content,
+                )
+                .unwrap();
+            assert!(!html.contains("typ:"), "{html}");
+            // Code without the prefix isn't touched
+            assert!(html.contains("<code>plain</code>"), "{html}");
+            let start = html.find("Inline ").unwrap() + "Inline ".len();
+            html[start..html.find(" and").unwrap()].to_owned()
+        };
+
+        let code = inline("code");
+        assert!(
+            code.starts_with(r#"<code class="nohighlight hljs"><span"#)
+                && code.ends_with("</code>"),
+            "{code}"
+        );
+        let span = inline("span");
+        assert!(
+            span.starts_with(r#"<span class="nohighlight hljs"><span"#)
+                && span.ends_with("</span>"),
+            "{span}"
+        );
+        let none = inline("none");
+        assert!(
+            none.starts_with("<span style=") && !none.contains("hljs"),
+            "{none}"
+        );
+        assert_eq!(
+            code.strip_prefix(r#"<code class="nohighlight hljs">"#)
+                .and_then(|code| code.strip_suffix("</code>")),
+            Some(none.as_str())
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn repeated_broken_blocks_are_reported_once() {
+        let book = TestBook::with_typst(
+            "repeated-error",
+            "echo 'error: unknown variable: oops' >&2; exit 1",
+        );
+        let broken = "```typ\n#oops\n```\n";
+        let twice = format!("{broken}\n{broken}");
+
+        let error = book
+            .chapters(
+                "strict = true\nshared_cache = true",
+                &[("intro.md", &twice), ("guide/usage.md", broken)],
+            )
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            error.matches("unknown variable: oops").count(),
+            1,
+            "{error}"
+        );
+        assert!(
+            error.contains("The same block is used 3 times, in chapters"),
+            "{error}"
+        );
+        assert_eq!(book.typst_input().matches("#oops").count(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn font_settings_become_typst_arguments() {
+        let book = TestBook::with_typst(
+            "font-args",
+            &format!("echo \"$@\" > \"$0-args\"\n{STUB_RENDER}"),
+        );
+        fs::create_dir_all(book.root.join("src/fonts")).unwrap();
+        let args = || fs::read_to_string(book.root.join("typst-args")).unwrap();
+
+        book.html("", RENDERED).unwrap();
+        let src_fonts = format!("--font-path {}", book.root.join("src/fonts").display());
+        assert!(args().contains(&src_fonts), "{}", args());
+        assert!(!args().contains("--ignore-system-fonts"), "{}", args());
+        let images = book.images();
+
+        book.html(
+            "font_paths = [\"fonts\", \"../shared/fonts\"]\nignore_system_fonts = true",
+            RENDERED,
+        )
+        .unwrap();
+        let args = args();
+        for fonts in [
+            src_fonts,
+            format!("--font-path {}", book.root.join("fonts").display()),
+            format!(
+                "--font-path {}",
+                book.root.join("../shared/fonts").display()
+            ),
+        ] {
+            assert!(args.contains(&fonts), "{args}");
+        }
+        assert!(args.contains("--ignore-system-fonts"), "{args}");
+        // The images are made again with the fonts
+        assert_eq!(book.images().len(), images.len() + 1);
+    }
+
+    #[test]
+    fn block_spacing_controls_the_break_and_margin() {
+        let book = TestBook::new("block-spacing");
+        let spacing = |setting: &str| {
+            let markdown = book
+                .markdown(&format!("block_spacing = \"{setting}\""), RENDERED)
+                .unwrap();
+            // The hard break is written as a line of two spaces
+            (
+                markdown.ends_with("\n  \n"),
+                markdown.contains("margin-bottom: 0.5em"),
+            )
+        };
+
+        assert_eq!(spacing("break"), (true, true));
+        assert_eq!(spacing("margin"), (false, true));
+        assert_eq!(spacing("none"), (false, false));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn editing_an_imported_file_renders_the_block_again() {
+        let book = TestBook::with_typst("imported-edit", STUB_RENDER);
+        let block = "```typ\n#import \"utils.typ\": x\n#x\n```\n";
+
+        book.write("src/utils.typ", "#let x = 1");
+        book.html("", block).unwrap();
+        book.html("", block).unwrap();
+        assert_eq!(book.typst_input().matches("#x").count(), 1);
+
+        book.write("src/utils.typ", "#let x = 2");
+        book.html("", block).unwrap();
+        assert_eq!(book.typst_input().matches("#x").count(), 1);
+        assert_eq!(book.images().len(), 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn wrapper_bg_replaces_the_quote_background() {
+        let book = TestBook::with_typst("wrapper-bg", STUB_RENDER);
+
+        let html = book.html("", RENDERED).unwrap();
+        assert!(
+            html.contains("padding: 0.5em; background: var(--quote-bg);"),
+            "{html}"
+        );
+
+        let html = book
+            .html("wrapper_bg = \"var(--sidebar-bg)\"", RENDERED)
+            .unwrap();
+        assert!(
+            html.contains("padding: 0.5em; background: var(--sidebar-bg);"),
+            "{html}"
+        );
+        assert!(!html.contains("--quote-bg"), "{html}");
+        // The image keeps its own backing
+        assert!(html.contains("background: white;"), "{html}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn index_lists_every_rendered_block() {
+        let book = TestBook::with_typst("index", STUB_RENDER);
+        let path = book.root.join("typst-index.json");
+
+        book.chapters("", &[("intro.md", RENDERED)]).unwrap();
+        assert!(!path.exists());
+
+        let captioned = "```typ,caption=Data flow,alt=Two arrows\n= Flow\n```\n";
+        book.chapters(
+            "emit_index = true",
+            &[
+                (
+                    "intro.md",
+                    &format!("{captioned}\nText\n\n```typ-norender\n= Plain\n```\n"),
+                ),
+                ("guide/usage.md", RENDERED),
+            ],
+        )
+        .unwrap();
+        let index: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+        let hash = |entry: usize| index[entry]["hash"].as_str().unwrap().to_owned();
+        assert_eq!(
+            index,
+            serde_json::json!([
+                {
+                    "chapter": "index",
+                    "path": "intro.md",
+                    "hash": hash(0),
+                    "files": [format!("typst-img/{}-1.svg", hash(0))],
+                    "caption": "Data flow",
+                    "alt": "Two arrows",
+                },
+                {
+                    "chapter": "index",
+                    "path": "guide/usage.md",
+                    "hash": hash(1),
+                    "files": [format!("guide/typst-img/{}-1.svg", hash(1))],
+                    "caption": null,
+                    "alt": null,
+                },
+            ])
+        );
+        assert_ne!(hash(0), hash(1));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn scale_sizes_the_image() {
+        let book = TestBook::with_typst("scale", STUB_RENDER);
+        // The stub's images are 10pt, 13.3px
+        let img_style = |settings: &str, info: &str| {
+            let html = book
+                .html(settings, &format!("```{info}\n= Title\n```\n"))
+                .unwrap();
+            let img = &html[html.find("<img").unwrap()..];
+            let start = img.find("style=\"").unwrap() + "style=\"".len();
+            img[start..start + img[start..].find('"').unwrap()].to_owned()
+        };
+
+        assert_eq!(
+            img_style("scale = 1.5", "typ"),
+            "background: white; width: 20px; max-width: 100%; height: auto;"
+        );
+        assert_eq!(
+            img_style("scale = 1.5", "typ,scale=0.75"),
+            "background: white; width: 10px; max-width: 100%; height: auto;"
+        );
+        assert_eq!(
+            img_style("", "typ,scale=-1"),
+            "background: white; max-width: 500pt; width: 100%; height: auto;"
+        );
+        assert!(
+            book.warnings()
+                .iter()
+                .any(|w| w.starts_with("Ignoring scale=-1")),
+            "{:?}",
+            book.warnings()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn chapters_without_a_path_are_left_alone() {
+        let book = TestBook::with_typst("pathless", STUB_RENDER);
+        let draft = "```typ\n= Draft\n```\n";
+        let mut pathless = Book::new();
+        pathless.push_item(Chapter::new_draft("Draft", vec![]));
+        if let Some(BookItem::Chapter(chapter)) = pathless.items.last_mut() {
+            chapter.content = draft.to_owned();
+        }
+        pathless.push_item(Chapter::new(
+            book.name,
+            RENDERED.to_owned(),
+            "intro.md",
+            vec![],
+        ));
+
+        let pathless = book.run("", pathless).unwrap();
+        let contents = pathless
+            .iter()
+            .filter_map(|item| match item {
+                BookItem::Chapter(chapter) => Some(chapter.content.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        // mdBook doesn't give drafts to preprocessors, nothing is rendered for them
+        assert_eq!(contents[0], draft);
+        assert!(contents[1].contains("<img"), "{}", contents[1]);
+        let input = book.typst_input();
+        assert!(
+            !input.contains("= Draft") && input.contains("= Title"),
+            "{input}"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn nohighlight_blocks_are_plain_but_rendered() {
+        let book = TestBook::with_typst("nohighlight", STUB_RENDER);
+        let html = book
+            .html("", "```typ,nohighlight\n= Title <a>\n```\n")
+            .unwrap();
+        assert!(html.contains("= Title &lt;a&gt;"), "{html}");
+        assert!(!html.contains("<span"), "{html}");
+        assert!(html.contains("<img"), "{html}");
+        assert_eq!(book.images().len(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn renders_of_all_chapters_complete_and_fail_together() {
+        let mut book = TestBook::with_typst("all-chapters", STUB_RENDER);
+        let chapters = [
+            ("a.md", "```typ\n= One\n```\n\n```typ\n= Two\n```\n"),
+            ("b.md", "```typ\n= Three\n```\n"),
+        ];
+        let html = book.chapters("strict = true", &chapters).unwrap();
+        assert!(html.iter().all(|html| html.contains("<img")), "{html:?}");
+        assert_eq!(book.images().len(), 3);
+
+        // Every failure is reported, not only the first
+        book.stub_typst("echo 'error: broken' >&2\nexit 1");
+        let chapters = chapters.map(|(path, content)| (path, content.replace('=', "==")));
+        let chapters = chapters
+            .each_ref()
+            .map(|(path, content)| (*path, content.as_str()));
+        let error = book
+            .chapters("strict = true", &chapters)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(error.matches("error: broken").count(), 3, "{error}");
+        for src in ["== One", "== Two", "== Three"] {
+            assert!(error.contains(src), "{error}");
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn align_sets_the_text_align_of_the_box() {
+        let book = TestBook::with_typst("align", STUB_RENDER);
+        let blocks = "```typ\n= One\n```\n\n```typ,align=right\n= Two\n```\n\n```typ,align=top\n= Three\n```\n";
+
+        let html = book.html("", blocks).unwrap();
+        let aligns = html
+            .match_indices("text-align: ")
+            .map(|(i, _)| html[i..].split(';').next().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            aligns,
+            [
+                "text-align: center",
+                "text-align: right",
+                "text-align: center"
+            ]
+        );
+
+        let html = book.html("align = \"left\"", blocks).unwrap();
+        assert_eq!(html.matches("text-align: left;").count(), 2, "{html}");
+        assert_eq!(html.matches("text-align: right;").count(), 1, "{html}");
+        assert!(
+            book.warnings()
+                .iter()
+                .any(|w| w.starts_with("Ignoring align=top")),
+            "{:?}",
+            book.warnings()
+        );
+    }
+
+    #[test]
+    fn bare_output_leaves_out_pre_and_code() {
+        let book = TestBook::new("bare");
+        let block = "```typ,hl=2\n#let a = 1\n#let b = 2\n```\n";
+
+        let html = book
+            .html("bare_output = true\nline_numbers = true", block)
+            .unwrap();
+        assert!(!html.contains("<pre") && !html.contains("<code"), "{html}");
+        assert!(html.contains("<span style="), "{html}");
+        assert_eq!(
+            html.matches(r#"<span class="typst-lineno""#).count(),
+            2,
+            "{html}"
+        );
+        assert_eq!(
+            html.matches(r#"<span class="highlighted-line""#).count(),
+            1,
+            "{html}"
+        );
+
+        let html = book.html("", block).unwrap();
+        assert!(html.contains("<pre") && html.contains("<code"), "{html}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn show_errors_inline_puts_the_error_under_the_block() {
+        let book = TestBook::with_typst(
+            "inline-error",
+            "echo 'error: expected <b> & co' >&2\nexit 1",
+        );
+        let html = book.html("show_errors_inline = true", RENDERED).unwrap();
+        let code = html.find("<code").unwrap();
+        let error = html.find(r#"<div class="typst-error""#).unwrap();
+        assert!(code < error, "{html}");
+        assert!(
+            html[error..].contains("error: expected &lt;b&gt; &amp; co"),
+            "{html}"
+        );
+
+        // Otherwise it's only logged
+        let html = book.html("", RENDERED).unwrap();
+        assert!(!html.contains("typst-error"), "{html}");
+    }
+
+    /// A `.tmTheme` with a foreground and a color for comments
+    const TM_THEME: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>name</key>
+    <string>Brand</string>
+    <key>settings</key>
+    <array>
+        <dict>
+            <key>settings</key>
+            <dict>
+                <key>foreground</key>
+                <string>#123456</string>
+                <key>background</key>
+                <string>#fedcba</string>
+            </dict>
+        </dict>
+        <dict>
+            <key>scope</key>
+            <string>comment</string>
+            <key>settings</key>
+            <dict>
+                <key>foreground</key>
+                <string>#abcdef</string>
+            </dict>
+        </dict>
+    </array>
+</dict>
+</plist>
+"#;
+
+    #[test]
+    fn theme_file_colors_are_used() {
+        let book = TestBook::new("theme-file");
+        book.write("theme/brand.tmTheme", TM_THEME);
+        let block = "```typ\n#let x = 1 // Comment\n```\n";
+
+        let html = book
+            .html("theme_file = \"theme/brand.tmTheme\"", block)
+            .unwrap();
+        assert!(html.contains("color:#123456;"), "{html}");
+        assert!(html.contains("color:#abcdef;"), "{html}");
+
+        // A block's own file is relative to its chapter
+        let html = book
+            .html(
+                "",
+                &block.replace("typ", "typ,theme_file=../theme/brand.tmTheme"),
+            )
+            .unwrap();
+        assert!(html.contains("color:#abcdef;"), "{html}");
+
+        let error = book.error("theme_file = \"theme/missing.tmTheme\"", block);
+        assert!(error.contains("missing.tmTheme"), "{error}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn title_is_an_escaped_tooltip_of_every_page() {
+        let book = TestBook::with_typst("title", STUB_THREE_PAGES);
+        let html = book
+            .html("", "```typ,title=<b> \"Sales\" & more\n= Title\n```\n")
+            .unwrap();
+        let title = r#" title="&lt;b&gt; &quot;Sales&quot; &amp; more""#;
+        assert_eq!(html.matches("<img").count(), 3, "{html}");
+        assert_eq!(html.matches(title).count(), 3, "{html}");
+        assert!(!html.contains("<b>"), "{html}");
+
+        // No tooltip without it
+        let html = book.html("", RENDERED).unwrap();
+        assert!(!html.contains(" title="), "{html}");
+    }
+
+    #[test]
+    fn highlighted_code_has_no_language_class() {
+        let book = TestBook::new("no-language-class");
+        let html = book
+            .html("", "Inline `{typ} #x`.\n\n```typ\n= Title\n```\n")
+            .unwrap();
+        assert_eq!(
+            html.matches(r#"class="nohighlight hljs""#).count(),
+            2,
+            "{html}"
+        );
+        assert!(!html.contains("language-"), "{html}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pages_shows_only_the_picked_pages() {
+        let book = TestBook::with_typst("pages", STUB_THREE_PAGES);
+        let html = book.html("", "```typ,pages=2\n= Title\n```\n").unwrap();
+        assert_eq!(html.matches("<img").count(), 1, "{html}");
+        assert!(html.contains(r#"-2.svg""#), "{html}");
+        assert!(html.contains("(page 2)"), "{html}");
+
+        let html = book.html("", "```typ,pages=3-5\n= Title\n```\n").unwrap();
+        assert_eq!(html.matches("<img").count(), 1, "{html}");
+        assert!(html.contains(r#"-3.svg""#), "{html}");
+        assert!(
+            book.warnings()
+                .iter()
+                .any(|w| w.contains("has only 3 pages")),
+            "{:?}",
+            book.warnings()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn show_preamble_in_source_shows_what_is_compiled() {
+        let book = TestBook::with_typst("show-preamble", STUB_RENDER);
+        let settings = "preamble = \"#let helper = 42\"";
+        let block = "```typ,hl=1\n#helper\n```\n";
+
+        let html = book.html(settings, block).unwrap();
+        assert!(!text(&html).contains("#let helper = 42"), "{html}");
+        assert!(book.typst_input().contains("#let helper = 42"));
+
+        let html = book
+            .html(
+                &format!("{settings}\nshow_preamble_in_source = true"),
+                block,
+            )
+            .unwrap();
+        let code = text(&html);
+        let preamble = code
+            .find("#let helper = 42")
+            .unwrap_or_else(|| panic!("{html}"));
+        assert!(preamble < code.find("#helper\n").unwrap(), "{code}");
+        // `hl=` counts the lines of the block's own code
+        let emphasized = html
+            .split(r#"<span class="highlighted-line""#)
+            .nth(1)
+            .unwrap();
+        let emphasized = &emphasized[..emphasized.find('\n').unwrap()];
+        assert_eq!(text(&format!("<span{emphasized}")), "#helper");
+        // The image is the same, the preamble was compiled before as well
+        assert!(book.typst_input().is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn filename_scheme_names_images_with_a_hash_suffix() {
+        let book = TestBook::with_typst("Filename Scheme", STUB_RENDER);
+        let blocks =
+            "```typ,caption=Data flow!\n= One\n```\n\n```typ,caption=Data flow!\n= Two\n```\n";
+        book.html("filename_scheme = \"{chapter}-{caption}\"", blocks)
+            .unwrap();
+
+        let images = book.images();
+        let [first, second] = images.as_slice() else {
+            panic!("{images:?}");
+        };
+        for image in &images {
+            let hash = image
+                .strip_prefix("filename-scheme-data-flow_")
+                .and_then(|rest| rest.strip_suffix("-1.svg"))
+                .unwrap_or_else(|| panic!("{images:?}"));
+            assert_eq!(hash.len(), SHORT_HASH_LEN, "{hash}");
+            assert!(hash.chars().all(|c| c.is_ascii_hexdigit()), "{hash}");
+        }
+        // The same name, but different code
+        assert_ne!(first, second);
+
+        book.html("filename_scheme = \"{chapter}-{page}\"", blocks)
+            .unwrap();
+        assert!(
+            book.warnings()
+                .iter()
+                .any(|w| w.starts_with("Ignoring filename_scheme")),
+            "{:?}",
+            book.warnings()
+        );
+    }
+
+    #[test]
+    fn copy_buttons_are_hidden_until_the_script_shows_them() {
+        let book = TestBook::new("copy-button");
+        let blocks = "```typ\n#let a = \"<1>\"\n```\n\n```typ\n#let b = 2\n```\n";
+
+        let html = book.html("copy_button = true", blocks).unwrap();
+        let buttons = html
+            .match_indices("<button")
+            .map(|(i, _)| &html[i..i + html[i..].find('>').unwrap()])
+            .collect::<Vec<_>>();
+        assert_eq!(buttons.len(), 2, "{html}");
+        for button in &buttons {
+            assert!(
+                button.contains(r#"class="clip-button typst-copy""#),
+                "{button}"
+            );
+            assert!(button.contains("display: none;"), "{button}");
+        }
+        assert!(
+            buttons[0].contains(r##"data-source="#let a = &quot;&lt;1&gt;&quot;""##),
+            "{}",
+            buttons[0]
+        );
+        // Without scripts the code is still there to select
+        assert!(
+            text(&html).contains("#let a = &quot;&lt;1&gt;&quot;"),
+            "{html}"
+        );
+        assert_eq!(html.matches(COPY_BUTTON_SCRIPT).count(), 1, "{html}");
+
+        let html = book.html("", blocks).unwrap();
+        assert!(
+            !html.contains("<button") && !html.contains("<script"),
+            "{html}"
+        );
+    }
+
+    #[test]
+    fn fenced_and_indented_defaults_are_separate() {
+        let book = TestBook::new("code-defaults");
+        let content = "```\n#let fenced = 1\n```\n\nProse:\n\n    #let indented = 2\n";
+
+        for (fenced, indented) in [(false, false), (true, false), (false, true), (true, true)] {
+            let html = book
+                .html(
+                    &format!("typst_default = {fenced}\nindented_default = {indented}"),
+                    content,
+                )
+                .unwrap();
+            let blocks = html.split("<pre").skip(1).collect::<Vec<_>>();
+            let [fenced_block, indented_block] = blocks.as_slice() else {
+                panic!("{html}");
+            };
+            let highlighted = |block: &str| block.contains("<span style=");
+            assert_eq!(highlighted(fenced_block), fenced, "{html}");
+            assert_eq!(highlighted(indented_block), indented, "{html}");
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn picture_has_the_svg_source_and_the_png_img() {
+        let book = TestBook::with_typst("picture", STUB_RENDER);
+        let html = book
+            .html(
+                "format = \"png\"\nformats = [\"svg\"]\npicture = true\nalign = \"left\"",
+                "```typ,alt=Chart,title=Sales\n= Title\n```\n",
+            )
+            .unwrap();
+
+        let images = book.images();
+        let [png, svg] = images.as_slice() else {
+            panic!("{images:?}");
+        };
+        assert_eq!(png.replace(".png", ".svg"), *svg);
+
+        let start = html.find("<picture>").unwrap_or_else(|| panic!("{html}"));
+        let picture = &html[start..start + html[start..].find("</picture>").unwrap()];
+        let source = picture.find("<source").unwrap();
+        let img = picture.find("<img").unwrap();
+        assert!(source < img, "{picture}");
+        assert!(
+            picture[source..img].contains(&format!(r#"srcset="typst-img/{svg}""#)),
+            "{picture}"
+        );
+        assert!(picture[source..img].contains("image/svg+xml"), "{picture}");
+        let img = &picture[img..];
+        assert!(img.contains(&format!(r#"src="typst-img/{png}""#)), "{img}");
+        assert!(
+            img.contains(r#"alt="Chart""#) && img.contains(r#"title="Sales""#),
+            "{img}"
+        );
+        assert!(html[..start].contains("text-align: left;"), "{html}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn skip_render_on_offline_keeps_only_the_source() {
+        let mut book = TestBook::with_typst(
+            "offline",
+            "echo 'error: failed to download package (Network Error)' >&2\nexit 1",
+        );
+        let block = "```typ\n#import \"@preview/cetz:0.3.0\"\n= Title\n```\n";
+
+        let error = book.error("strict = true", block);
+        assert!(error.contains("failed to download package"), "{error}");
+
+        let html = book
+            .html("strict = true\nskip_render_on_offline = true", block)
+            .unwrap();
+        assert!(
+            html.contains("<code") && html.contains("<span style="),
+            "{html}"
+        );
+        assert!(!html.contains("<img"), "{html}");
+        assert!(book.images().is_empty());
+        assert!(
+            book.warnings()
+                .iter()
+                .any(|w| w.contains("is skipped, Typst can't download a package")),
+            "{:?}",
+            book.warnings()
+        );
+
+        // Genuine errors still fail
+        book.stub_typst("echo 'error: unexpected end of block' >&2\nexit 1");
+        let error = book.error("strict = true\nskip_render_on_offline = true", block);
+        assert!(error.contains("unexpected end of block"), "{error}");
+    }
+
+    #[test]
+    fn include_background_adds_the_theme_background() {
+        let book = TestBook::new("include-background");
+        // Background of Solarized (dark)
+        let background = "#002b36";
+        let content = "Inline `#x`.\n\n```typ\n#let x = 1\n```\n";
+
+        let html = book.html("", content).unwrap();
+        assert!(!html.contains(background), "{html}");
+        assert!(!html.contains("background-color"), "{html}");
+
+        let html = book.html("include_background = true", content).unwrap();
+        assert!(
+            html.contains(&format!(
+                r#"<code class="nohighlight hljs" style="background: {background}">"#
+            )),
+            "{html}"
+        );
+        assert!(
+            html.contains(&format!("background-color:{background};")),
+            "{html}"
+        );
+        let inline = &html[..html.find("<pre").unwrap()];
+        assert!(inline.contains(background), "{inline}");
+        // Text on the theme's background keeps the theme's foreground
+        assert!(html.contains(";color:#839496;"), "{html}");
+        assert!(!html.contains("var(--fg)"), "{html}");
+    }
+
+    /// A TrueType font named `family` with one empty glyph, enough to be read and converted
+    fn minimal_ttf(family: &str) -> Vec<u8> {
+        /// Big-endian `values`, each of its own number of bytes
+        fn be(values: &[(u32, usize)]) -> Vec<u8> {
+            values
+                .iter()
+                .flat_map(|&(value, bytes)| value.to_be_bytes()[4 - bytes..].to_vec())
+                .collect()
+        }
+
+        let head = [
+            be(&[
+                (0x10000, 4),
+                (0x10000, 4),
+                (0, 4),
+                (0x5F0F3CF5, 4),
+                (0, 2),
+                (1000, 2),
+            ]),
+            vec![0; 24], // dates and bounds
+            be(&[(0, 2), (8, 2), (2, 2), (0, 2), (0, 2)]),
+        ]
+        .concat();
+        let hhea = [
+            be(&[(0x10000, 4), (800, 2), (0xff38, 2)]),
+            vec![0; 26],
+            be(&[(1, 2)]),
+        ]
+        .concat();
+        let maxp = [be(&[(0x10000, 4), (1, 2)]), vec![0; 26]].concat();
+        let family = family
+            .encode_utf16()
+            .flat_map(u16::to_be_bytes)
+            .collect::<Vec<_>>();
+        // The family in Windows' Unicode encoding
+        let name = [
+            be(&[(0, 2), (1, 2), (18, 2), (3, 2), (1, 2), (0x409, 2), (1, 2)]),
+            be(&[(family.len() as u32, 2), (0, 2)]),
+            family,
+        ]
+        .concat();
+        let tables = [
+            (b"glyf", vec![]),
+            (b"head", head),
+            (b"hhea", hhea),
+            (b"hmtx", be(&[(500, 2), (0, 2)])),
+            (b"loca", be(&[(0, 2), (0, 2)])),
+            (b"maxp", maxp),
+            (b"name", name),
+        ];
+
+        let mut font = be(&[
+            (0x10000, 4),
+            (tables.len() as u32, 2),
+            (64, 2),
+            (2, 2),
+            (48, 2),
+        ]);
+        let header = font.len() + 16 * tables.len();
+        let mut data = vec![];
+        for (tag, mut table) in tables {
+            font.extend(tag);
+            let offset = header + data.len();
+            font.extend(be(&[(0, 4), (offset as u32, 4), (table.len() as u32, 4)]));
+            table.resize(table.len().next_multiple_of(4), 0);
+            data.extend(table);
+        }
+        font.extend(data);
+        font
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn embed_fonts_adds_font_faces_of_the_converted_fonts() {
+        let book = TestBook::with_typst("embed-fonts", STUB_RENDER);
+        fs::create_dir_all(book.root.join("src/fonts")).unwrap();
+        fs::write(
+            book.root.join("src/fonts/TestSans.ttf"),
+            minimal_ttf("Test Sans"),
+        )
+        .unwrap();
+
+        let html = book
+            .html("embed_fonts = true\ninline_svg = true", RENDERED)
+            .unwrap();
+        let converted = fs::read_dir(book.root.join("src/typst-fonts"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        let [woff2] = converted.as_slice() else {
+            panic!("{converted:?}");
+        };
+        assert!(
+            woff2.starts_with("testsans-") && woff2.ends_with(".woff2"),
+            "{woff2}"
+        );
+        let rule = format!(
+            r#"@font-face {{ font-family: "Test Sans"; src: url("typst-fonts/{woff2}") format("woff2"); font-weight: 400; font-style: normal; }}"#
+        );
+        assert!(html.contains(&rule), "{html}");
+
+        // Only inline SVGs use them
+        let html = book.html("embed_fonts = true", RENDERED).unwrap();
+        assert!(!html.contains("@font-face"), "{html}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn oversized_images_get_a_warning_naming_the_block() {
+        let book = TestBook::with_typst("oversized", STUB_RENDER);
+        let limits = "[preprocessor.typst-highlight.limits]\nmax_image_bytes = 10";
+        let oversized = |warnings: Vec<String>| {
+            warnings
+                .into_iter()
+                .filter(|w| w.contains("larger than limits.max_image_bytes = 10"))
+                .collect::<Vec<_>>()
+        };
+
+        let html = book.html(limits, RENDERED).unwrap();
+        assert!(html.contains("<img"), "{html}");
+        let [warning] = oversized(book.warnings()).try_into().unwrap();
+        assert!(warning.contains("The block at test.md:2"), "{warning}");
+        assert!(warning.contains("-1.svg is 39 bytes"), "{warning}");
+
+        // Cached images aren't checked again
+        book.html(limits, RENDERED).unwrap();
+        assert_eq!(oversized(book.warnings()).len(), 1);
+
+        let error = book.error(
+            &format!("strict = true\n{limits}"),
+            "```typ\n= Other\n```\n",
+        );
+        assert!(
+            error.contains("larger than limits.max_image_bytes"),
+            "{error}"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn text_size_is_set_after_the_page_and_rerenders() {
+        let book = TestBook::with_typst("text-size", STUB_RENDER);
+
+        book.html("render_text_size = \"11pt\"", RENDERED).unwrap();
+        let input = book.typst_input();
+        assert!(
+            input.starts_with(&format!("{PREAMBLE}#set text(size: 11pt)\n")),
+            "{input}"
+        );
+        let images = book.images();
+
+        // The same size is cached, another one is a new image
+        book.html("render_text_size = \"11pt\"", RENDERED).unwrap();
+        assert!(book.typst_input().is_empty());
+        book.html("", "```typ,textsize=9pt\n= Title\n```\n")
+            .unwrap();
+        assert!(book.typst_input().contains("#set text(size: 9pt)\n"));
+        assert_eq!(book.images().len(), images.len() + 1);
+
+        // It is the only preamble of blocks without one
+        book.html("", "```typ,nopreamble,textsize=1.5em\n= Title\n```\n")
+            .unwrap();
+        assert_eq!(book.typst_input(), "#set text(size: 1.5em)\n\n= Title\n");
+
+        book.html("", "```typ,textsize=11\n= Title\n```\n").unwrap();
+        assert!(!book.typst_input().contains("#set text"));
+        assert!(
+            book.warnings()
+                .iter()
+                .any(|w| w.starts_with("Ignoring textsize=11")),
+            "{:?}",
+            book.warnings()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn classes_go_to_the_box_of_rendered_blocks_and_the_pre_of_others() {
+        let book = TestBook::with_typst("classes-attr", STUB_RENDER);
+        let html = book
+            .html(
+                "",
+                "```typ,class=wide \"><b> figure\n= One\n```\n\n```typ,norender,class=wide figure\n= Two\n```\n",
+            )
+            .unwrap();
+        let (rendered, plain) = html.split_once("</div></div>\n").unwrap();
+
+        assert!(
+            rendered.starts_with(r#"<div class="wide figure""#),
+            "{rendered}"
+        );
+        assert!(rendered.contains(r#"<pre style="#), "{rendered}");
+        assert!(rendered.contains("<img"), "{rendered}");
+        assert!(
+            plain.contains(r#"<pre class="wide figure" style="#),
+            "{plain}"
+        );
+        assert!(
+            plain.contains(r#"<code class="nohighlight hljs">"#),
+            "{plain}"
+        );
+        assert!(!html.contains("<b>"), "{html}");
+        assert!(
+            book.warnings()
+                .iter()
+                .any(|w| w.starts_with("Ignoring class `\"><b>`")),
+            "{:?}",
+            book.warnings()
+        );
+    }
+
+    #[test]
+    fn highlighter_post_process_transforms_the_output() {
+        let theme = theme(DEFAULT_THEME).unwrap();
+        let highlighter =
+            Highlighter::new(&theme).post_process(|html| html.replace("marker", "MARKER"));
+
+        let html = highlighter.highlight("// marker").unwrap();
+        assert!(html.contains("MARKER"));
+        assert!(!html.contains("marker"));
+
+        // Without a hook the output is that of `highlight_typst`
+        let plain = Highlighter::new(&theme).highlight("// marker").unwrap();
+        assert_eq!(
+            plain,
+            highlight_typst("// marker", &HighlightOptions::new(&theme)).unwrap()
+        );
+    }
 }
@@ -1,9 +1,13 @@
-use clap::{Arg, ArgMatches, Command};
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use mdbook_preprocessor::book::{Book, Chapter};
+use mdbook_preprocessor::config::Config;
 use mdbook_preprocessor::errors::Result;
-use mdbook_preprocessor::{Preprocessor, MDBOOK_VERSION};
+use mdbook_preprocessor::{Preprocessor, PreprocessorContext, MDBOOK_VERSION};
 use mdbook_typst_highlight::TypstHighlight;
 use semver::{Version, VersionReq};
+use std::fs;
 use std::io;
+use std::path::{Path, PathBuf};
 use std::process;
 
 pub fn make_app() -> Command {
@@ -14,17 +18,48 @@ pub fn make_app() -> Command {
                 .arg(Arg::new("renderer").required(true))
                 .about("Check whether a renderer is supported by this preprocessor"),
         )
+        .subcommand(
+            Command::new("render")
+                .arg(
+                    Arg::new("dir")
+                        .default_value(".")
+                        .help("Root directory of the book, with book.toml"),
+                )
+                .arg(
+                    Arg::new("dry-run")
+                        .long("dry-run")
+                        .action(ArgAction::SetTrue)
+                        .help("Only list the blocks that would be compiled"),
+                )
+                .about("Render the Typst blocks of a book into the cache without building it"),
+        )
 }
 
 fn main() {
     let matches = make_app().get_matches();
 
+    // Warnings and errors are shown by default, `RUST_LOG` changes that.
+    // Rendering on its own also reports what was rendered
+    let filter = if matches.subcommand_matches("render").is_some() {
+        "info"
+    } else {
+        "warn"
+    };
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(filter)).init();
+
     let preprocessor = TypstHighlight;
 
     if let Some(sub_args) = matches.subcommand_matches("supports") {
         handle_supports(&preprocessor, sub_args);
+    } else if let Some(sub_args) = matches.subcommand_matches("render") {
+        let dir = sub_args.get_one::<String>("dir").expect("Has a default");
+        let dry_run = sub_args.get_flag("dry-run");
+        if let Err(e) = handle_render(&preprocessor, Path::new(dir), dry_run) {
+            log::error!("{}", e);
+            process::exit(1);
+        }
     } else if let Err(e) = handle_preprocessing(&preprocessor) {
-        eprintln!("{}", e);
+        log::error!("{}", e);
         process::exit(1);
     }
 }
@@ -36,8 +71,8 @@ fn handle_preprocessing(pre: &dyn Preprocessor) -> Result<()> {
     let version_req = VersionReq::parse(MDBOOK_VERSION)?;
 
     if !version_req.matches(&book_version) {
-        eprintln!(
-            "Warning: The {} plugin was built against version {} of mdbook, \
+        log::warn!(
+            "The {} plugin was built against version {} of mdbook, \
              but we're being called from version {}",
             pre.name(),
             MDBOOK_VERSION,
@@ -64,3 +99,50 @@ fn handle_supports(pre: &dyn Preprocessor, sub_args: &ArgMatches) -> ! {
         process::exit(1);
     }
 }
+
+/// Runs the preprocessor over every chapter in the book sources, as the HTML renderer would,
+/// so that the following builds find the images in the cache
+fn handle_render(pre: &dyn Preprocessor, root: &Path, dry_run: bool) -> Result<()> {
+    let mut config = Config::from_disk(root.join("book.toml"))?;
+    if dry_run {
+        config.set("preprocessor.typst-highlight.dry_run", true)?;
+    }
+
+    if config.get::<bool>("preprocessor.typst-highlight.render")? != Some(true) {
+        log::warn!("Rendering is disabled, set preprocessor.typst-highlight.render = true");
+    }
+
+    let src = root.join(&config.book.src);
+    let mut chapters = vec![];
+    find_chapters(&src, &src, &mut chapters)?;
+
+    let mut book = Book::new();
+    for path in chapters {
+        let content = fs::read_to_string(src.join(&path))?;
+        let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+        book.push_item(Chapter::new(&name, content, path, vec![]));
+    }
+
+    let ctx = PreprocessorContext::new(root.to_path_buf(), config, "html".to_owned());
+    pre.run(&ctx, book)?;
+
+    Ok(())
+}
+
+/// Markdown files under `dir`, relative to `src`, except `SUMMARY.md`
+fn find_chapters(src: &Path, dir: &Path, chapters: &mut Vec<PathBuf>) -> Result<()> {
+    let mut entries = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+
+    for path in entries {
+        if path.is_dir() {
+            find_chapters(src, &path, chapters)?;
+        } else if path.extension().is_some_and(|e| e == "md") && !path.ends_with("SUMMARY.md") {
+            chapters.push(path.strip_prefix(src)?.to_path_buf());
+        }
+    }
+
+    Ok(())
+}
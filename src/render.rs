@@ -0,0 +1,1070 @@
+//! Compiling blocks with Typst and checking the images
+
+use async_io::Timer;
+use async_process::{Command, Stdio};
+use futures::future::{self, FutureExt, LocalBoxFuture};
+use futures::io::AsyncWriteExt;
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::future::Future;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::pin::pin;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use mdbook_preprocessor::errors::Result;
+use unicode_normalization::UnicodeNormalization as _;
+
+use crate::cache::{cache_key, dependencies};
+use crate::{
+    DownloadFormat, ImageFormat, PreprocessSettings, RunState, UnicodeNormalization, SHORT_HASH_LEN,
+};
+
+/// Counts rendered blocks of the book, so that a long build doesn't look frozen
+pub(crate) struct Progress {
+    verbose: bool,
+    /// Blocks whose images were already in the cache
+    hits: Cell<usize>,
+    /// Blocks compiled by Typst
+    misses: Cell<usize>,
+    /// Blocks reported so far, see `Progress::report`
+    done: Cell<usize>,
+    /// The cache is ignored, so misses are re-renders
+    forced: bool,
+    /// Nothing is compiled, every block is listed
+    dry_run: bool,
+}
+
+impl Progress {
+    pub(crate) fn new(verbose: bool, forced: bool, dry_run: bool) -> Self {
+        Self {
+            verbose,
+            forced,
+            dry_run,
+            hits: Cell::new(0),
+            misses: Cell::new(0),
+            done: Cell::new(0),
+        }
+    }
+
+    /// Counts a block while the book is prepared, so the total is known before rendering starts
+    fn count(&self, hit: bool) {
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.set(counter.get() + 1);
+    }
+
+    fn total(&self) -> usize {
+        self.hits.get() + self.misses.get()
+    }
+
+    /// Logs a block when its rendering starts
+    fn report(&self, chapter: &str, hit: bool) {
+        self.done.set(self.done.get() + 1);
+        if self.dry_run {
+            log::info!(
+                "Diagram {}/{} in chapter \"{}\" ({})",
+                self.done.get(),
+                self.total(),
+                chapter,
+                if hit {
+                    "cache hit"
+                } else {
+                    "would be compiled"
+                }
+            );
+        } else if self.verbose {
+            log::info!(
+                "Rendering diagram {}/{} in chapter \"{}\" ({})",
+                self.done.get(),
+                self.total(),
+                chapter,
+                match (hit, self.forced) {
+                    (true, _) => "cache hit",
+                    (false, true) => "forced re-render",
+                    (false, false) => "cache miss",
+                }
+            );
+        }
+    }
+
+    pub(crate) fn summary(&self) {
+        if self.dry_run {
+            log::info!(
+                "Dry run, nothing was rendered: {} blocks, {} in the cache, {} would be compiled",
+                self.total(),
+                self.hits.get(),
+                self.misses.get()
+            );
+            return;
+        }
+        log::info!(
+            "Rendered {} blocks: {} from the cache, {} compiled",
+            self.total(),
+            self.hits.get(),
+            self.misses.get()
+        );
+    }
+}
+
+/// A block of a chapter to render, see `render_block`
+pub(crate) struct RenderRequest {
+    /// Typst source of the block, wrapped for its mode
+    pub(crate) src: String,
+    /// Where the rendered files are written
+    pub(crate) dir: PathBuf,
+    /// Directory of the chapter in the book sources, imports are resolved against it
+    pub(crate) source_dir: PathBuf,
+    /// Name of the chapter
+    pub(crate) chapter: String,
+    pub(crate) location: BlockLocation,
+    /// Readable part of the file names, see `filename_scheme`
+    pub(crate) slug: Option<String>,
+    pub(crate) preamble: Option<String>,
+    /// `sys.inputs` of the block
+    pub(crate) inputs: BTreeMap<String, String>,
+}
+
+/// Returns the rendered file without the page number and extension and the exact text
+/// given to Typst, along with the futures that render it
+pub(crate) fn render_block(
+    request: RenderRequest,
+    run: &RunState,
+) -> (
+    PathBuf,
+    String,
+    Vec<LocalBoxFuture<'static, Option<String>>>,
+) {
+    let RenderRequest {
+        src,
+        mut dir,
+        source_dir,
+        chapter: name,
+        mut location,
+        slug,
+        preamble,
+        inputs,
+    } = request;
+    let settings = run.settings;
+    let normalized = match settings.unicode_normalization {
+        UnicodeNormalization::None => src.clone(),
+        _ => src.nfc().collect::<String>(),
+    };
+    // Images of different resolutions are cached separately
+    let mut key_args = settings.typst_args();
+    if settings.format == ImageFormat::Png
+        || settings.extra_formats().contains(&DownloadFormat::Png)
+    {
+        key_args.push(format!("--ppi={}", settings.ppi()));
+    }
+    // As configured, so that the names don't depend on where the book is
+    key_args.extend(
+        settings
+            .font_paths
+            .iter()
+            .map(|path| format!("--font-path={}", path.display())),
+    );
+    if settings.ignore_system_fonts {
+        key_args.push("--ignore-system-fonts".to_owned());
+    }
+    // Edits of imported files have to re-render the block too
+    let root = settings.typst_root.as_deref().unwrap_or(&source_dir);
+    let dependencies = dependencies(
+        &format!(
+            "{}\n{}",
+            preamble.as_deref().unwrap_or_default(),
+            normalized
+        ),
+        root,
+    );
+    let hash = cache_key(
+        &normalized,
+        preamble.as_deref(),
+        &inputs,
+        &dependencies,
+        &key_args,
+        &settings.typst_version,
+    );
+    // Blocks with the same readable name still differ in the hash
+    let filename = match slug {
+        Some(slug) => format!("{slug}_{}", &hash[..SHORT_HASH_LEN]),
+        None => hash,
+    };
+    let src = if settings.unicode_normalization == UnicodeNormalization::Source {
+        normalized
+    } else {
+        src
+    };
+
+    let mut output = dir.clone();
+    output.push("typst-img");
+
+    let mut check = output.clone();
+    let mut cut_output = output.clone();
+    cut_output.push(filename.clone());
+
+    let extension = settings.format.extension();
+    output.push(filename.clone() + "-{n}." + extension);
+    check.push(filename.clone() + "-1." + extension);
+
+    // A duplicate gets the images of the first block, they are ready by the time pages are finished
+    let first = run.manifests.first_seen(&cut_output, &name);
+    let render = first && (settings.forced() || !run.manifests.is_valid(&cut_output));
+
+    // The other formats share the name and the source, each is compiled unless it's cached
+    let extras = settings
+        .extra_formats()
+        .into_iter()
+        .map(|format| (format, format.files(&cut_output)))
+        .filter(|(_, (_, check))| first && (settings.forced() || !check.exists()))
+        .collect::<Vec<_>>();
+    let render_extras = !extras.is_empty();
+
+    let hidpi = cut_output.with_file_name(filename.clone() + "-2x-{n}.png");
+    let hidpi_check = cut_output.with_file_name(filename.clone() + "-2x-1.png");
+    let render_hidpi = first && settings.srcset() && (render || !hidpi_check.exists());
+
+    dir.push("typst-src");
+    dir.push(filename.clone() + ".typ");
+
+    let input = match preamble {
+        Some(preamble) => {
+            location.preamble_lines = preamble.matches('\n').count() + 1;
+            format!("{preamble}\n{src}")
+        }
+        None => src.clone(),
+    };
+
+    // The saved source is for downloading and debugging, Typst reads it from stdin,
+    // so the block is rendered even if it can't be saved
+    let source_error = (!settings.dry_run
+        && (render || render_extras || (settings.source_download && !dir.exists())))
+    .then(|| {
+        fs::create_dir_all(dir.parent().unwrap())
+            .and_then(|_| fs::write(&dir, &input))
+            .err()
+            .map(|e| write_error(&name, &dir, e))
+    })
+    .flatten();
+
+    let fonts = settings.src_dir.join("fonts");
+    let fonts = fonts.exists().then_some(fonts);
+
+    // The block is reported once rendering starts, when the total is already known
+    let hit = !render && !render_extras && !render_hidpi;
+    run.progress.count(hit);
+    let progress = run.progress.clone();
+    let block = name.clone();
+    let mut commands = vec![async move {
+        progress.report(&block, hit);
+        None
+    }
+    .boxed_local()];
+    commands.extend(source_error.map(|error| future::ready(Some(error)).boxed_local()));
+
+    // Only the cached images are shown
+    if settings.dry_run {
+        return (cut_output, input, commands);
+    }
+
+    if (render || render_extras || render_hidpi)
+        && let Err(e) = fs::create_dir_all(output.parent().unwrap())
+    {
+        let error = write_error(&name, output.parent().unwrap(), e);
+        commands.push(future::ready(Some(error)).boxed_local());
+        return (cut_output, input, commands);
+    }
+
+    let mut hidpi = render_hidpi.then(|| {
+        let ppi = Some(settings.ppi() * 2.0);
+        let command = typst_command(
+            &source_dir,
+            &hidpi,
+            fonts.as_deref(),
+            ppi,
+            &inputs,
+            settings,
+        );
+        run_typst(
+            command,
+            name.clone(),
+            &location,
+            &input,
+            &src,
+            hidpi_check,
+            settings,
+        )
+        .boxed_local()
+    });
+
+    if render {
+        let ppi = (settings.format == ImageFormat::Png).then(|| settings.ppi());
+        let command = typst_command(
+            &source_dir,
+            &output,
+            fonts.as_deref(),
+            ppi,
+            &inputs,
+            settings,
+        );
+        let optimize = settings.optimize_svg && settings.format == ImageFormat::Svg;
+        let single_page = settings.single_page;
+        let max_image_bytes = settings.limits.max_image_bytes;
+        let strict = settings.strict;
+        let source = format!("{}:{}", location.file, location.line);
+        let file = cut_output.clone();
+        let block = name.clone();
+        let snippet = src.clone();
+        let manifests = run.manifests.clone();
+        // The 2x images are rendered only if the 1x ones are fine, so that errors aren't reported twice
+        let hidpi = hidpi.take();
+        commands.push(
+            run_typst(
+                command,
+                name.clone(),
+                &location,
+                &input,
+                &src,
+                check,
+                settings,
+            )
+            .map(move |error| {
+                if let Some(error) = error {
+                    return manifests.annotate(&file, error);
+                }
+                // Typst succeeded, but e.g. the document was empty. In strict mode it's an error already
+                if get_images(file.clone(), extension).next().is_none() {
+                    log::warn!(
+                        "A block in chapter \"{block}\" produced no images:\n{}",
+                        snippet.trim_end()
+                    );
+                    return None;
+                }
+                // Only fresh renders get here, cached images are already optimized
+                if optimize {
+                    let dir = file.parent().unwrap().to_path_buf();
+                    for name in get_images(file.clone(), "svg") {
+                        optimize_svg(&dir.join(name));
+                    }
+                }
+                // Oversized images of strict builds aren't recorded, so that the next build checks them again
+                if let Some(report) =
+                    max_image_bytes.and_then(|max| oversized_images(&file, extension, max, &source))
+                {
+                    if strict {
+                        return Some(format!("Error at chapter \"{block}\"\n\n{report}"));
+                    }
+                    log::warn!("{}", report.trim_end());
+                }
+                manifests.record(&file, extension);
+                single_page
+                    .then(|| single_page_error(&file, &block, extension))
+                    .flatten()
+            })
+            .then(move |error| match (error, hidpi) {
+                (None, Some(hidpi)) => hidpi,
+                (error, _) => future::ready(error).boxed_local(),
+            })
+            .boxed_local(),
+        );
+    } else if settings.single_page {
+        let error = single_page_error(&cut_output, &name, extension);
+        commands.push(future::ready(error).boxed_local());
+    }
+
+    commands.extend(hidpi);
+
+    for (format, (output, check)) in extras {
+        let ppi = (format == DownloadFormat::Png).then(|| settings.ppi());
+        let command = typst_command(
+            &source_dir,
+            &output,
+            fonts.as_deref(),
+            ppi,
+            &inputs,
+            settings,
+        );
+        let manifests = run.manifests.clone();
+        let file = cut_output.clone();
+        commands.push(
+            run_typst(
+                command,
+                name.clone(),
+                &location,
+                &input,
+                &src,
+                check,
+                settings,
+            )
+            .map(move |error| error.and_then(|error| manifests.annotate(&file, error)))
+            .boxed_local(),
+        );
+    }
+
+    (cut_output, input, commands)
+}
+
+/// Compile error of a block whose files can't be written, e.g. because the disk is full
+fn write_error(chapter: &str, path: &Path, error: std::io::Error) -> String {
+    format!(
+        "Error at chapter \"{chapter}\"\n\nCan't write {}: {error}\n",
+        path.display()
+    )
+}
+
+/// Strips what browsers don't need from a rendered SVG, it is left as is on failure
+pub(crate) fn optimize_svg(path: &Path) {
+    if let Err(e) = fs::read_to_string(path).and_then(|svg| fs::write(path, minify_svg(&svg))) {
+        log::warn!("Can't optimize {}, keeping it as is: {}", path.display(), e);
+    }
+}
+
+/// Removes the XML declaration, comments, metadata and indentation between tags.
+/// Text of `xml:space="preserve"` elements and CDATA sections is kept as it is
+fn minify_svg(svg: &str) -> String {
+    let mut res = String::with_capacity(svg.len());
+    let mut rest = svg.trim_start();
+
+    if rest.starts_with("<?xml")
+        && let Some(end) = rest.find("?>")
+    {
+        rest = &rest[end + 2..];
+    }
+
+    // Number of open elements, and at which of them whitespace started to matter
+    let mut depth: usize = 0;
+    let mut preserved_at = None;
+    while !rest.is_empty() {
+        let Some(tag) = rest.find('<') else {
+            res.push_str(rest.trim_end());
+            break;
+        };
+        // Text with a line break between tags is only formatting
+        let text = &rest[..tag];
+        if preserved_at.is_some() || !(text.trim().is_empty() && text.contains('\n')) {
+            res.push_str(text);
+        }
+        rest = &rest[tag..];
+
+        if let Some(after) = rest.strip_prefix("<!--") {
+            rest = after.find("-->").map_or("", |end| &after[end + 3..]);
+        } else if let Some(after) = rest.strip_prefix("<![CDATA[") {
+            let end = after
+                .find("]]>")
+                .map_or(rest.len(), |end| "<![CDATA[".len() + end + 3);
+            res.push_str(&rest[..end]);
+            rest = &rest[end..];
+        } else if rest.starts_with("<metadata")
+            && let Some(end) = rest.find("</metadata>")
+        {
+            rest = &rest[end + "</metadata>".len()..];
+        } else {
+            let element = &rest[..tag_len(rest)];
+            if element.starts_with("</") {
+                depth = depth.saturating_sub(1);
+                if preserved_at.is_some_and(|at| at > depth) {
+                    preserved_at = None;
+                }
+            } else if !element.starts_with("<!") && !element.ends_with("/>") {
+                depth += 1;
+                if preserved_at.is_none()
+                    && (element.contains("xml:space=\"preserve\"")
+                        || element.contains("xml:space='preserve'"))
+                {
+                    preserved_at = Some(depth);
+                }
+            }
+            res.push_str(element);
+            rest = &rest[element.len()..];
+        }
+    }
+
+    res
+}
+
+/// Length of the tag `svg` starts with, `>` in quoted attribute values doesn't end it
+fn tag_len(svg: &str) -> usize {
+    let mut quote = None;
+    for (i, c) in svg.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(open), _) if c == open => quote = None,
+            (None, '>') => return i + 1,
+            _ => {}
+        }
+    }
+    svg.len()
+}
+
+/// `typst c` invocation reading the source from stdin, the output format is chosen by its extension.
+/// The root is the chapter directory, so that relative imports work as if the block was a file there
+fn typst_command(
+    root: &Path,
+    output: &Path,
+    fonts: Option<&Path>,
+    ppi: Option<f64>,
+    inputs: &BTreeMap<String, String>,
+    settings: &PreprocessSettings,
+) -> Command {
+    let mut res = Command::new(settings.typst());
+    res.arg("c")
+        .arg("-")
+        .arg("--root")
+        .arg(settings.typst_root.as_deref().unwrap_or(root))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+
+    if let Some(ppi) = ppi {
+        res.arg("--ppi").arg(ppi.to_string());
+    }
+
+    for fonts in fonts
+        .into_iter()
+        .chain(settings.font_dirs.iter().map(PathBuf::as_path))
+    {
+        res.arg("--font-path").arg(fonts);
+    }
+
+    if settings.ignore_system_fonts {
+        res.arg("--ignore-system-fonts");
+    }
+
+    if let Some(packages) = &settings.package_path {
+        res.arg("--package-path").arg(packages);
+    }
+
+    if let Some(cache) = &settings.package_cache_path {
+        res.arg("--package-cache-path").arg(cache);
+    }
+
+    for (key, value) in inputs {
+        res.arg("--input").arg(format!("{key}={value}"));
+    }
+
+    // The output goes last, so that it isn't taken for a value of an extra argument
+    res.args(settings.typst_args()).arg(output);
+
+    res
+}
+
+/// Where the code of a rendered block is in the book sources, so that Typst's errors
+/// can point there instead of to its stdin
+#[derive(Clone)]
+pub(crate) struct BlockLocation {
+    /// Chapter file, relative to the book sources
+    pub(crate) file: String,
+    /// Line of the chapter where the code starts, 1-based
+    pub(crate) line: usize,
+    /// Lines of the preamble before the code, including the line break after it
+    pub(crate) preamble_lines: usize,
+    /// Lines added by the mode, see `TypstMode::wrap`
+    pub(crate) wrap_lines: usize,
+}
+
+impl BlockLocation {
+    /// Replaces `<stdin>:line:column` in Typst's output with the place in the chapter.
+    /// Errors in the preamble point to `<preamble>:line:column`
+    fn rewrite(&self, output: &str) -> String {
+        const STDIN: &str = "<stdin>:";
+
+        let mut rewritten = String::with_capacity(output.len());
+        let mut rest = output;
+        while let Some(start) = rest.find(STDIN) {
+            rewritten.push_str(&rest[..start]);
+            let after = &rest[start + STDIN.len()..];
+            let digits = after
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(after.len());
+            let Ok(line) = after[..digits].parse::<usize>() else {
+                rewritten.push_str(STDIN);
+                rest = after;
+                continue;
+            };
+            if line <= self.preamble_lines {
+                rewritten.push_str(&format!("<preamble>:{line}"));
+            } else {
+                // Lines of the wrapper before the code are its first line
+                let code_line = (line - self.preamble_lines).saturating_sub(self.wrap_lines);
+                rewritten.push_str(&format!(
+                    "{}:{}",
+                    self.file,
+                    self.line + code_line.saturating_sub(1)
+                ));
+            }
+            rest = &after[digits..];
+        }
+        rewritten.push_str(rest);
+
+        rewritten
+    }
+}
+
+/// Pause before the first retry of `render_retries`, it doubles with every next one
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Whether Typst failed to fetch a package or the package index, which may pass on a retry
+/// and is the error of `skip_render_on_offline`. Other errors mentioning the network still fail
+fn is_package_download_error(stderr: &[u8]) -> bool {
+    String::from_utf8_lossy(stderr)
+        .to_lowercase()
+        .contains("failed to download package")
+}
+
+/// Runs the compiler, the resulting future yields its errors, if any.
+/// The process is spawned only when the future is polled,
+/// so that the number of simultaneous renders can be limited
+fn run_typst(
+    mut command: Command,
+    name: String,
+    location: &BlockLocation,
+    input: &str,
+    src: &str,
+    expected: PathBuf,
+    settings: &PreprocessSettings,
+) -> impl Future<Output = Option<String>> + use<> {
+    let timeout = settings.limits.render_timeout();
+    let strict = settings.strict;
+    let features = !settings.features.is_empty();
+    let retries = settings.render_retries;
+    let skip_offline = settings.skip_render_on_offline;
+    let location = location.clone();
+    let input = input.to_owned();
+    // The offending snippet is shown only in strict mode, when the build fails anyway
+    let src = strict.then(|| src.to_owned());
+
+    async move {
+        let mut attempt = 0;
+        let (mut output, offline) = loop {
+            let run = async {
+                let mut child = command.spawn()?;
+                if let Some(mut stdin) = child.stdin.take() {
+                    stdin.write_all(input.as_bytes()).await?;
+                }
+                child.output().await
+            };
+
+            let expired = async {
+                Timer::after(timeout).await;
+                None
+            };
+            let (finished, _) = future::select(pin!(run.map(Some)), pin!(expired))
+                .await
+                .factor_first();
+
+            let offline = matches!(&finished, Some(Ok(output))
+                if !output.status.success() && is_package_download_error(&output.stderr));
+            let retry = attempt < retries && offline;
+
+            let output = match finished {
+                // Warnings don't fail the block, they are only logged
+                Some(Ok(output)) if output.status.success() => {
+                    let warnings = String::from_utf8_lossy(&output.stderr);
+                    if !warnings.trim().is_empty() {
+                        log::warn!(
+                            "Typst warned about a block in chapter \"{name}\":\n{}",
+                            location.rewrite(&warnings).trim_end()
+                        );
+                    }
+                    vec![]
+                }
+                // Typst may fail without a word on stderr, then whatever it said is the error
+                Some(Ok(output)) => {
+                    let mut message = output.stdout;
+                    message.extend(output.stderr);
+                    if message.iter().all(u8::is_ascii_whitespace) {
+                        message = format!("Typst failed with {}\n", output.status).into_bytes();
+                    }
+                    message
+                }
+                Some(Err(e)) => format!("Can't run Typst: {e}\n").into_bytes(),
+                // Dropping the output future kills the process, see `kill_on_drop`
+                None => format!(
+                    "Rendering {} timed out after {} s\n",
+                    expected.display(),
+                    timeout.as_secs()
+                )
+                .into_bytes(),
+            };
+
+            if retry {
+                attempt += 1;
+                log::warn!(
+                    "Rendering {} failed to download a package, retrying ({attempt}/{retries})",
+                    expected.display()
+                );
+                Timer::after(RETRY_BACKOFF * 2u32.pow(attempt - 1)).await;
+                continue;
+            }
+            break (output, offline);
+        };
+
+        if skip_offline && offline {
+            log::warn!(
+                "Rendering a block in chapter \"{name}\" is skipped, Typst can't download a package:\n{}",
+                location.rewrite(&String::from_utf8_lossy(&output)).trim_end()
+            );
+            return None;
+        }
+
+        if strict && output.is_empty() && !expected.exists() {
+            output = format!("Typst produced no {}\n", expected.display()).into_bytes();
+        }
+
+        (!output.is_empty()).then(|| {
+            let mut error = format!(
+                "Error at chapter \"{}\"\n\n{}",
+                name,
+                location.rewrite(&String::from_utf8_lossy(&output))
+            );
+            if features && error.contains("'--features'") {
+                error += "\nThe installed Typst doesn't support `features`, update it or remove the setting\n";
+            }
+            if let Some(src) = src {
+                error += &format!("\nin snippet:\n{src}\n");
+            }
+            error
+        })
+    }
+}
+
+/// Names of the rendered pages in page order. Pages are looked up in the directory,
+/// so that the rest are found even if some are missing after a partially failed compilation
+pub(crate) fn get_images(src: PathBuf, extension: &'static str) -> impl Iterator<Item = String> {
+    let fbase = src.file_name().unwrap().to_string_lossy().into_owned() + "-";
+
+    let mut pages = fs::read_dir(src.parent().unwrap())
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            let n = name
+                .strip_prefix(&fbase)?
+                .strip_suffix(extension)?
+                .strip_suffix('.')?
+                .parse::<usize>()
+                .ok()?;
+            Some((n, name))
+        })
+        .collect::<Vec<_>>();
+    pages.sort_unstable();
+
+    pages.into_iter().map(|(_, name)| name)
+}
+
+/// Error for a block that produced several pages when `single_page` is set
+fn single_page_error(file: &Path, name: &str, extension: &'static str) -> Option<String> {
+    let pages = get_images(file.to_path_buf(), extension).count();
+    (pages > 1).then(|| {
+        format!(
+            "Error at chapter \"{name}\"\n\nA block produced {pages} pages, but `single_page` is set\n"
+        )
+    })
+}
+
+/// Report of the pages of a block larger than `limits.max_image_bytes`,
+/// `source` is where the block is, such as `chapter.md:12`
+fn oversized_images(
+    file: &Path,
+    extension: &'static str,
+    max: u64,
+    source: &str,
+) -> Option<String> {
+    let dir = file.parent().unwrap();
+    let oversized = get_images(file.to_path_buf(), extension)
+        .filter_map(|image| {
+            let size = fs::metadata(dir.join(&image)).ok()?.len();
+            (size > max).then(|| format!("{image} is {size} bytes\n"))
+        })
+        .collect::<String>();
+
+    (!oversized.is_empty()).then(|| {
+        format!(
+            "The block at {source} rendered images larger than limits.max_image_bytes = {max}:\n{oversized}"
+        )
+    })
+}
+
+/// Compares a freshly rendered image with the reference one at `golden`.
+/// With `golden_update` the reference is overwritten instead.
+pub(crate) fn check_golden(
+    dir: &Path,
+    name: &str,
+    golden: &Path,
+    settings: &PreprocessSettings,
+) -> Result<()> {
+    let actual = fs::read(dir.join(name))?;
+
+    if settings.golden_update {
+        fs::create_dir_all(golden.parent().unwrap())?;
+        fs::write(golden, actual)?;
+        return Ok(());
+    }
+
+    let expected =
+        fs::read(golden).map_err(|e| anyhow!("Can't read {}: {}", golden.display(), e))?;
+
+    // Images are compared line by line, which is good enough for SVG
+    let actual = actual.split(|&b| b == b'\n').collect::<Vec<_>>();
+    let expected = expected.split(|&b| b == b'\n').collect::<Vec<_>>();
+
+    let total = actual.len().max(expected.len());
+    let differing = (0..total)
+        .filter(|&i| actual.get(i) != expected.get(i))
+        .count();
+    let ratio = differing as f64 / total as f64;
+
+    if ratio > settings.golden_threshold {
+        Err(anyhow!(
+            "{name} differs from {} ({:.2}% of lines)",
+            golden.display(),
+            ratio * 100.0
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Size of a rendered image in CSS pixels, from the `<svg>` tag or the PNG header
+pub(crate) fn image_size(path: &Path) -> Option<(u32, u32)> {
+    match path.extension()?.to_str()? {
+        "svg" => {
+            // The tag is at the very start of Typst's SVGs
+            let mut head = vec![0; 1024];
+            let read = File::open(path).ok()?.read(&mut head).ok()?;
+            let head = String::from_utf8_lossy(&head[..read]);
+            let tag_start = head.find("<svg")?;
+            let tag = &head[tag_start..tag_start + head[tag_start..].find('>')?];
+            let (_, width) = svg_length(tag, "width")?;
+            let (_, height) = svg_length(tag, "height")?;
+            // 1pt = 4/3px
+            Some((
+                (width * 4.0 / 3.0).round() as u32,
+                (height * 4.0 / 3.0).round() as u32,
+            ))
+        }
+        "png" => {
+            // Width and height are the first fields of IHDR, right after the signature
+            let mut head = [0; 24];
+            File::open(path).ok()?.read_exact(&mut head).ok()?;
+            if !head.starts_with(b"\x89PNG\r\n\x1a\n") || &head[12..16] != b"IHDR" {
+                return None;
+            }
+            let width = u32::from_be_bytes(head[16..20].try_into().ok()?);
+            let height = u32::from_be_bytes(head[20..24].try_into().ok()?);
+            Some((width, height))
+        }
+        _ => None,
+    }
+}
+
+/// Finds `attr="<number>pt"` in the opening `<svg ...>` tag,
+/// returns the whole attribute and its value
+pub(crate) fn svg_length<'a>(tag: &'a str, attr: &str) -> Option<(&'a str, f64)> {
+    let pattern = format!(r#" {attr}=""#);
+    let attr_start = tag.find(&pattern)?;
+    let start = attr_start + pattern.len();
+    let end = start + tag[start..].find('"')?;
+    let value = tag[start..end].strip_suffix("pt")?.parse().ok()?;
+    Some((&tag[attr_start..=end], value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Shaped like the SVGs of Typst 0.13: nested groups indented by four spaces, glyphs
+    /// as symbols in `<defs>`
+    const TYPST_SVG: &str = r##"<svg class="typst-doc" viewBox="0 0 283.46 36.13" width="283.46pt" height="36.13pt" xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" xmlns:h5="http://www.w3.org/1999/xhtml">
+    <path class="typst-shape" fill="#ffffff" fill-rule="nonzero" d="M 0 0 v 36.13 h 283.46 v -36.13 Z "/>
+    <g>
+        <g transform="translate(14.17 22.68)">
+            <g class="typst-text" transform="scale(1, -1)">
+                <use xlink:href="#g1" x="0" fill="#000000" fill-rule="nonzero"/>
+                <use xlink:href="#g2" x="6.42" fill="#000000" fill-rule="nonzero"/>
+                <use xlink:href="#g1" x="11.9" fill="#000000" fill-rule="nonzero"/>
+            </g>
+        </g>
+    </g>
+    <defs id="glyph">
+        <symbol id="g1" overflow="visible">
+            <path d="M 1.892 0 L 1.892 7.2930007 L 4.6530004 7.2930007 Q 5.9510007 7.2930007 6.6770005 6.6605005 Q 7.4030004 6.0280004 7.4030004 4.9060006 Z "/>
+        </symbol>
+        <symbol id="g2" overflow="visible">
+            <path d="M 0.64900005 0 L 0.64900005 5.269 L 1.5730001 5.269 L 1.5730001 0 Z M 0.64900005 6.3140006 L 0.64900005 7.2930007 L 1.5730001 7.2930007 Z "/>
+        </symbol>
+    </defs>
+</svg>
+"##;
+
+    #[test]
+    fn minify_svg_keeps_the_drawing() {
+        let minified = minify_svg(&format!(
+            "<?xml version=\"1.0\"?>\n<!-- Typst -->\n{}",
+            TYPST_SVG.replacen("<g>", "<metadata><rdf/></metadata>\n    <g>", 1)
+        ));
+        assert!(
+            minified.starts_with("<svg class=\"typst-doc\""),
+            "{minified}"
+        );
+        assert!(!minified.contains(['\n']), "{minified}");
+        assert!(!minified.contains("metadata") && !minified.contains("Typst -->"));
+        // Everything but the formatting is left as it was
+        let elements = |svg: &str| svg.lines().map(str::trim).collect::<String>();
+        assert_eq!(minified, elements(TYPST_SVG));
+    }
+
+    #[test]
+    fn minify_svg_keeps_text_and_attributes() {
+        let svg = r##"<svg class="typst-doc" viewBox="0 0 100 20" width="100pt" height="20pt" xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink">
+    <style type="text/css"><![CDATA[
+        .typst-text { font: 11pt "Libertinus Serif" }
+    ]]></style>
+    <a xlink:href="https://example.com/?a>b&amp;c" data-note='1 > 0'>
+        <g class="typst-text" transform="translate(10 15)">
+            <text xml:space="preserve" x="0">  two
+    lines <tspan font-weight="bold">
+  </tspan> </text>
+        </g>
+    </a>
+</svg>
+"##;
+        assert_eq!(
+            minify_svg(svg),
+            concat!(
+                r#"<svg class="typst-doc" viewBox="0 0 100 20" width="100pt" height="20pt" xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink">"#,
+                r#"<style type="text/css"><![CDATA[
+        .typst-text { font: 11pt "Libertinus Serif" }
+    ]]></style>"#,
+                r#"<a xlink:href="https://example.com/?a>b&amp;c" data-note='1 > 0'>"#,
+                r#"<g class="typst-text" transform="translate(10 15)">"#,
+                r#"<text xml:space="preserve" x="0">  two
+    lines <tspan font-weight="bold">
+  </tspan> </text>"#,
+                "</g></a></svg>"
+            )
+        );
+    }
+
+    #[test]
+    fn only_package_downloads_are_download_errors() {
+        assert!(is_package_download_error(
+            b"error: failed to download package (Network Error: Dns Failed)"
+        ));
+        assert!(is_package_download_error(
+            b"error: Failed to download package index"
+        ));
+        assert!(!is_package_download_error(
+            b"error: unknown variable: network"
+        ));
+        assert!(!is_package_download_error(
+            b"error: failed to download font"
+        ));
+    }
+
+    #[test]
+    fn single_page_error_counts_the_pages() {
+        let dir = std::env::temp_dir().join(format!(
+            "mdbook-typst-highlight-pages-{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("block");
+        let error = || single_page_error(&file, "pages", "svg");
+
+        assert_eq!(error(), None);
+        fs::write(dir.join("block-1.svg"), "<svg/>").unwrap();
+        assert_eq!(error(), None);
+        for page in [2, 3] {
+            fs::write(dir.join(format!("block-{page}.svg")), "<svg/>").unwrap();
+        }
+        let error = error().unwrap();
+        assert!(error.contains("A block produced 3 pages"), "{error}");
+
+        // Pages after a missing one are still found
+        fs::remove_file(dir.join("block-1.svg")).unwrap();
+        assert_eq!(
+            get_images(file, "svg").collect::<Vec<_>>(),
+            ["block-2.svg", "block-3.svg"]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn image_size_reads_svg_and_png_headers() {
+        let dir = std::env::temp_dir().join(format!(
+            "mdbook-typst-highlight-size-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+
+        let svg = dir.join("block-1.svg");
+        fs::write(&svg, TYPST_SVG).unwrap();
+        assert_eq!(image_size(&svg), Some((378, 48)));
+
+        let png = dir.join("block-1.png");
+        let mut head = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        head.extend(640u32.to_be_bytes());
+        head.extend(120u32.to_be_bytes());
+        fs::write(&png, head).unwrap();
+        assert_eq!(image_size(&png), Some((640, 120)));
+
+        fs::write(&png, "not a png").unwrap();
+        assert_eq!(image_size(&png), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Size of SVGs before and after `optimize_svg`, of the ones in the directory
+    /// `TYPST_HIGHLIGHT_BENCH_SVGS` (such as `typst-img` of a rendered book) or of `TYPST_SVG`.
+    /// `cargo test --release -- --ignored --nocapture svg_size_reduction`
+    #[test]
+    #[ignore]
+    fn svg_size_reduction() {
+        let svgs = match std::env::var_os("TYPST_HIGHLIGHT_BENCH_SVGS") {
+            Some(dir) => fs::read_dir(dir)
+                .unwrap()
+                .flatten()
+                .map(|entry| entry.path())
+                .filter(|path| path.extension().is_some_and(|e| e == "svg"))
+                .map(|path| fs::read_to_string(path).unwrap())
+                .collect(),
+            None => vec![TYPST_SVG.to_owned()],
+        };
+        let before = svgs.iter().map(String::len).sum::<usize>();
+        let after = svgs.iter().map(|svg| minify_svg(svg).len()).sum::<usize>();
+        println!(
+            "{} SVGs: {before} B before, {after} B after, {:.1}% smaller",
+            svgs.len(),
+            100.0 * (before - after) as f64 / before as f64
+        );
+    }
+
+    #[test]
+    fn block_location_rewrites_stdin_positions() {
+        let location = BlockLocation {
+            file: "guide/intro.md".to_owned(),
+            line: 10,
+            preamble_lines: 2,
+            wrap_lines: 0,
+        };
+        assert_eq!(
+            location.rewrite("error: x\n  ┌─ <stdin>:3:7\n  ┌─ <stdin>:4:1"),
+            "error: x\n  ┌─ guide/intro.md:10:7\n  ┌─ guide/intro.md:11:1"
+        );
+        assert_eq!(location.rewrite("<stdin>:2:1"), "<preamble>:2:1");
+        // Only positions are rewritten
+        assert_eq!(location.rewrite("<stdin>: no line"), "<stdin>: no line");
+
+        // The line added by the mode before the code belongs to its first line
+        let wrapped = BlockLocation {
+            wrap_lines: 1,
+            ..location
+        };
+        assert_eq!(wrapped.rewrite("<stdin>:3:1"), "guide/intro.md:10:1");
+        assert_eq!(wrapped.rewrite("<stdin>:5:1"), "guide/intro.md:11:1");
+    }
+}